@@ -1,3 +1,4 @@
+# Generated by `colmena-health nixos-options`; don't edit by hand
 { lib, config, ... }:
 {
   options =
@@ -43,95 +44,205 @@
 
       oneOfTagged = definitions: types.oneOf (mapAttrDefs definitions);
 
-      alertPolicy = types.submodule {
-        options = {
-          checkInterval = mkOption {
-            type = types.int;
-            default = 300;
-          };
-          recheckInterval = mkOption {
-            type = types.int;
-            default = 30;
-          };
-        };
-      };
-      retryPolicy = types.submodule {
-        options = {
-          maxRetries = mkOption {
-            type = types.int;
-            default = 3;
-          };
-          initial = mkOption {
-            type = types.float;
-            default = 1.0;
-          };
-          multiplier = mkOption {
-            type = types.float;
-            default = 1.1;
-          };
-        };
-      };
       checkDefinitionCommon = {
         alertPolicy = mkOption {
-          type = alertPolicy;
-          default = {
-            checkInterval = 300;
-            recheckInterval = 30;
+          type = types.submodule {
+            options = {
+              checkInterval = mkOption {
+                type = types.number;
+                default = 300.0;
+                description = "The number of seconds between runs of a passing check";
+              };
+              failuresBeforeAlert = mkOption {
+                type = types.int;
+                default = 1;
+                description = "How many consecutive failed runs it takes to fire an alert";
+              };
+              flapDetection = mkOption {
+                type = types.nullOr (types.submodule {
+                  options = {
+                    threshold = mkOption {
+                      type = types.int;
+                      description = "A check changing state more than this many times within the window is flapping";
+                    };
+                    window = mkOption {
+                      type = types.int;
+                      description = "The number of recent runs to consider";
+                    };
+                  };
+                });
+                default = null;
+              };
+              jitter = mkOption {
+                type = types.number;
+                default = 0.0;
+                description = "Varies each wait between runs by up to this fraction of the interval either way, from 0 to 1";
+              };
+              recheckInterval = mkOption {
+                type = types.number;
+                default = 30.0;
+                description = "The number of seconds between runs of a failing check";
+              };
+              schedule = mkOption {
+                type = types.nullOr types.str;
+                default = null;
+                description = "A cron expression for when a passing check runs, e.g. `*/15 8-18 * * MON-FRI`, used instead of `checkInterval`";
+              };
+              splay = mkOption {
+                type = types.nullOr types.number;
+                default = null;
+                description = "Delays each check's first run by up to this many seconds, spreading checks out after startup";
+              };
+              successesBeforeResolve = mkOption {
+                type = types.int;
+                default = 1;
+                description = "How many consecutive successful runs it takes to resolve a firing alert";
+              };
+              timezone = mkOption {
+                type = types.nullOr types.str;
+                default = null;
+                description = "The IANA timezone `schedule` is in, e.g. `Europe/London`, defaulting to UTC";
+              };
+            };
           };
-        };
-        retryPolicy = mkOption {
-          type = retryPolicy;
           default = {
-            maxRetries = 3;
-            initial = 1.0;
-            multiplier = 1.1;
+            checkInterval = 300.0;
+            failuresBeforeAlert = 1;
+            flapDetection = null;
+            jitter = 0.0;
+            recheckInterval = 30.0;
+            schedule = null;
+            splay = null;
+            successesBeforeResolve = 1;
+            timezone = null;
           };
+          description = "How the check runs in alerting mode";
+        };
+        annotations = mkOption {
+          type = types.attrsOf types.str;
+          default = { };
+          description = "Extra annotations for the check's alerts";
         };
         checkTimeout = mkOption {
-          type = types.int;
-          default = 10;
+          type = types.number;
+          default = 10.0;
+          description = "The number of seconds before each run of the check times out";
+        };
+        failAfter = mkOption {
+          type = types.nullOr types.number;
+          default = null;
+          description = "Checks slower than this are reported as failures, even if they complete within `checkTimeout`";
         };
         labels = mkOption {
-          type = types.attrs;
+          type = types.attrsOf types.str;
           default = { };
+          description = "Arbitrary key/value data describing the check, used to select checks and as alert labels";
         };
-        annotations = mkOption {
-          type = types.attrs;
-          default = { };
+        retryPolicy = mkOption {
+          type = types.submodule {
+            options = {
+              deadline = mkOption {
+                type = types.nullOr types.number;
+                default = null;
+                description = "The number of seconds after the first attempt beyond which no retry starts, whatever `maxRetries` allows";
+              };
+              initial = mkOption {
+                type = types.number;
+                default = 1.0;
+                description = "The number of seconds to wait before the first retry";
+              };
+              jitter = mkOption {
+                type = types.enum [ "none" "full" "equal" ];
+                default = "none";
+                description = "How each wait before a retry is randomized: `none`, `full` or `equal`";
+              };
+              maxDelay = mkOption {
+                type = types.nullOr types.number;
+                default = null;
+                description = "The longest number of seconds to wait before any retry, however far the backoff has grown";
+              };
+              maxRetries = mkOption {
+                type = types.int;
+                default = 3;
+                description = "The number of retries before failing the check, or 0 to disable retries";
+              };
+              multiplier = mkOption {
+                type = types.number;
+                default = 1.1;
+                description = "Applied to the wait before each further retry, for exponential backoff";
+              };
+            };
+          };
+          default = {
+            deadline = null;
+            initial = 1.0;
+            jitter = "none";
+            maxDelay = null;
+            maxRetries = 3;
+            multiplier = 1.1;
+          };
+          description = "How failed runs of the check are retried before it's considered failed";
+        };
+        warnAfter = mkOption {
+          type = types.nullOr types.number;
+          default = null;
+          description = "Successful checks slower than this are reported as warnings";
         };
       };
 
       checkDefinition = oneOfTagged {
-        ssh = {
+        http = {
           options = {
             params = {
-              hostname = mkOption {
-                type = types.str;
-                default = config.networking.hostName;
-              };
-              command = mkOption { type = types.str; };
-              username = mkOption {
-                type = types.nullOr types.str;
+              certExpiryWarningDays = mkOption {
+                type = types.nullOr types.int;
                 default = null;
+                description = "Warn when the server's certificate expires within this many days, and fail once it has expired";
+              };
+              insecureIgnoreSSLCert = mkOption {
+                type = types.bool;
+                default = false;
+                description = "Accept invalid, expired or self-signed certificates";
+              };
+              url = mkOption {
+                type = types.str;
+                description = "The URL to request, which passes with any 2xx status";
               };
             };
           } // checkDefinitionCommon;
         };
-        http = {
+        dns = {
           options = {
             params = {
-              url = mkOption { type = types.str; };
-              insecureIgnoreSSLCert = mkOption {
-                type = types.bool;
-                default = false;
+              domain = mkOption {
+                type = types.str;
+                description = "The domain which has to resolve";
               };
             };
           } // checkDefinitionCommon;
         };
-        dns = {
+        ssh = {
           options = {
             params = {
-              domain = mkOption { type = types.str; };
+              command = mkOption {
+                type = types.str;
+                description = "The command to run on the target, which passes if it exits with 0";
+              };
+              hostname = mkOption {
+                type = types.str;
+                default = config.networking.hostName;
+                description = "The hostname of the target";
+              };
+              username = mkOption {
+                type = types.nullOr types.str;
+                default = null;
+                description = "The username to connect as, or whatever ssh picks from its config if not given";
+              };
+              warningExitCodes = mkOption {
+                type = types.listOf types.int;
+                default = [ ];
+                description = "Exit codes which indicate a warning rather than a failure";
+              };
             };
           } // checkDefinitionCommon;
         };
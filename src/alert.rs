@@ -1,77 +1,507 @@
+use std::fmt::{self, Display};
 use std::{collections::HashMap, time::Duration};
 
-use futures::stream::FuturesUnordered;
-use futures::StreamExt;
-use serde::Deserialize;
+use chrono::Utc;
+use chrono_tz::Tz;
+use croner::Cron;
+use log::{error, info, warn};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
 use serde_with::{serde_as, DurationSeconds};
 use simple_eyre::eyre::Result;
-use tokio::{sync::mpsc::UnboundedReceiver, time::sleep};
+use time::OffsetDateTime;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+use tokio::task::LocalSet;
+use tokio::time::sleep;
 
-use crate::{alertmanager, run_check, CheckInfo, CheckStatus, CheckUpdate, RunnableCheck};
+use crate::{alertmanager, control, notify, reload, run_check, CheckStatus, CheckUpdate, RunnableCheck};
 
 #[serde_as]
-#[derive(Clone, Deserialize, Debug)]
+#[derive(Clone, Deserialize, JsonSchema, Debug)]
+#[schemars(rename = "AlertingConfig")]
 #[serde(rename_all = "camelCase")]
 pub struct Config {
-    #[serde(rename = "baseURL")]
-    pub base_url: String,
+    #[serde(default)]
+    pub notifiers: Vec<notify::Config>,
+    /// Deprecated: a single Alertmanager API root, e.g. `http://alertmanager:9093/api/v1`, from before `notifiers`
+    #[serde(default, rename = "baseURL")]
+    pub base_url: Option<String>,
     #[serde_as(as = "DurationSeconds<f64>")]
+    #[schemars(with = "f64")]
     pub realert_interval: Duration,
     pub allow_output_annotation: bool,
+    #[serde(default)]
+    pub watchdog: Option<WatchdogConfig>,
+    #[serde(default)]
+    pub control: Option<control::Config>,
+}
+
+impl Config {
+    /// Every configured notifier, including an Alertmanager given by the deprecated `baseURL`
+    pub fn all_notifiers(&self) -> Vec<notify::Config> {
+        let mut notifiers = self.notifiers.clone();
+        if let Some(ref base_url) = self.base_url {
+            notifiers.push(notify::Config::Alertmanager(alertmanager::Config::from_base_url(
+                base_url,
+            )));
+        }
+
+        notifiers
+    }
+}
+
+fn default_stale_multiplier() -> f64 {
+    3.0
+}
+
+#[derive(Clone, Deserialize, JsonSchema, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct WatchdogConfig {
+    /// Labels for the always-firing heartbeat alert, merged over `alertname: Watchdog`
+    #[serde(default)]
+    pub labels: HashMap<String, String>,
+    /// A check is considered stale when it hasn't sent an update in this many check intervals
+    #[serde(default = "default_stale_multiplier")]
+    pub stale_multiplier: f64,
+}
+
+fn default_threshold() -> u32 {
+    1
+}
+
+fn default_check_interval() -> Duration {
+    Duration::from_secs(300)
+}
+
+fn default_recheck_interval() -> Duration {
+    Duration::from_secs(30)
+}
+
+/// A cron expression, e.g. `*/15 8-18 * * MON-FRI`
+#[derive(Clone, Deserialize, Serialize, Debug)]
+#[serde(try_from = "String", into = "String")]
+pub struct Schedule {
+    /// As written, since parsing normalizes the pattern
+    expression: String,
+    cron: Cron,
+}
+
+impl TryFrom<String> for Schedule {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let cron = Cron::new(&value)
+            .parse()
+            .map_err(|err| format!("Invalid schedule '{}': {}", value, err))?;
+
+        // Patterns like `0 0 30 2 *` parse, but never come around
+        cron.find_next_occurrence(&Utc::now(), false)
+            .map_err(|_| format!("Schedule '{}' never runs", value))?;
+
+        Ok(Schedule {
+            expression: value,
+            cron,
+        })
+    }
+}
+
+impl From<Schedule> for String {
+    fn from(schedule: Schedule) -> String {
+        schedule.expression
+    }
+}
+
+impl Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.expression)
+    }
+}
+
+impl Schedule {
+    /// How long until the schedule next comes around, in `timezone`
+    fn until_next(&self, timezone: Tz) -> Option<Duration> {
+        let now = Utc::now().with_timezone(&timezone);
+        let next = self.cron.find_next_occurrence(&now, false).ok()?;
+        (next - now).to_std().ok()
+    }
+
+    /// The shortest time between runs over roughly the coming week, in `timezone` if given or else UTC
+    pub fn shortest_gap(&self, timezone: Option<Tz>) -> Option<Duration> {
+        let now = Utc::now().with_timezone(&timezone.unwrap_or(Tz::UTC));
+        let runs: Vec<_> = self
+            .cron
+            .iter_after(now)
+            .take_while(|run| *run - now <= chrono::Duration::days(8))
+            .take(10_000)
+            .collect();
+
+        runs.windows(2)
+            .filter_map(|pair| (pair[1] - pair[0]).to_std().ok())
+            .min()
+    }
 }
 
 #[serde_as]
-#[derive(Clone, Deserialize, Debug)]
+#[derive(Clone, Deserialize, JsonSchema, Serialize, Debug)]
+#[schemars(rename = "AlertPolicy")]
 #[serde(rename_all = "camelCase")]
 pub struct Policy {
+    /// The number of seconds between runs of a passing check
     #[serde_as(as = "DurationSeconds<f64>")]
-    check_interval: Duration,
+    #[schemars(with = "f64")]
+    #[serde(default = "default_check_interval")]
+    pub check_interval: Duration,
+    /// The number of seconds between runs of a failing check
     #[serde_as(as = "DurationSeconds<f64>")]
-    recheck_interval: Duration,
+    #[schemars(with = "f64")]
+    #[serde(default = "default_recheck_interval")]
+    pub recheck_interval: Duration,
+    /// How many consecutive failed runs it takes to fire an alert
+    #[serde(default = "default_threshold")]
+    pub failures_before_alert: u32,
+    /// How many consecutive successful runs it takes to resolve a firing alert
+    #[serde(default = "default_threshold")]
+    pub successes_before_resolve: u32,
+    #[serde(default)]
+    pub flap_detection: Option<FlapDetection>,
+    /// Delays each check's first run by up to this many seconds, spreading checks out after startup
+    #[serde_as(as = "Option<DurationSeconds<f64>>")]
+    #[schemars(with = "Option<f64>")]
+    #[serde(default)]
+    pub splay: Option<Duration>,
+    /// Varies each wait between runs by up to this fraction of the interval either way, from 0 to 1
+    #[serde(default)]
+    pub jitter: f64,
+    /// A cron expression for when a passing check runs, e.g. `*/15 8-18 * * MON-FRI`, used instead of `checkInterval`
+    #[schemars(with = "Option<String>")]
+    #[serde(default)]
+    pub schedule: Option<Schedule>,
+    /// The IANA timezone `schedule` is in, e.g. `Europe/London`, defaulting to UTC
+    #[schemars(with = "Option<String>")]
+    #[serde(default)]
+    pub timezone: Option<Tz>,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Policy {
+            check_interval: default_check_interval(),
+            recheck_interval: default_recheck_interval(),
+            failures_before_alert: default_threshold(),
+            successes_before_resolve: default_threshold(),
+            flap_detection: None,
+            splay: None,
+            jitter: 0.0,
+            schedule: None,
+            timezone: None,
+        }
+    }
+}
+
+/// 64-bit FNV-1a, which unlike the standard library's hashers gives the same hash in every build
+fn fnv1a(bytes: impl IntoIterator<Item = u8>) -> u64 {
+    bytes.into_iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// The key a check's splay and jitter are derived from, given its identity, so they survive restarts and reloads
+pub fn spread_key(identity: &str) -> u64 {
+    fnv1a(identity.bytes())
+}
+
+/// A fraction in `[0, 1)` derived from a check's spread key, so each check's spread is the same from run to run
+fn spread(key: u64, salt: u64) -> f64 {
+    let hash = fnv1a(key.to_le_bytes().into_iter().chain(salt.to_le_bytes()));
+    (hash >> 11) as f64 / (1u64 << 53) as f64
+}
+
+impl Policy {
+    /// How long the check with spread `key` waits before its first run
+    pub fn startup_delay(&self, key: u64) -> Duration {
+        self.splay.unwrap_or_default().mul_f64(spread(key, 0))
+    }
+
+    /// The wait before the check with spread `key` next runs, jittered differently for each `iteration`
+    pub fn jittered(&self, interval: Duration, key: u64, iteration: u64) -> Duration {
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        interval.mul_f64(1.0 + jitter * (2.0 * spread(key, iteration) - 1.0))
+    }
+
+    /// The wait before the check with spread `key` next runs after passing, until the next scheduled time if it has one
+    ///
+    /// Scheduled checks are still spread out by their splay, so checks sharing a schedule don't all run at once.
+    pub fn check_wait(&self, key: u64, iteration: u64) -> Duration {
+        let scheduled = self
+            .schedule
+            .as_ref()
+            .and_then(|schedule| schedule.until_next(self.timezone.unwrap_or(Tz::UTC)));
+
+        match scheduled {
+            Some(until_next) => until_next + self.startup_delay(key),
+            None => self.jittered(self.check_interval, key, iteration),
+        }
+    }
+
+    /// The longest a check may go between updates without being considered stuck, other than waiting for its schedule
+    pub fn longest_wait(&self) -> Duration {
+        let interval = match self.schedule {
+            Some(_) => self.recheck_interval,
+            None => self.check_interval.max(self.recheck_interval),
+        };
+        interval
+            .mul_f64(1.0 + self.jitter.clamp(0.0, 1.0))
+            .max(self.splay.unwrap_or_default())
+    }
+}
+
+#[derive(Clone, Deserialize, JsonSchema, Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct FlapDetection {
+    /// The number of recent runs to consider
+    pub window: usize,
+    /// A check changing state more than this many times within the window is flapping
+    pub threshold: usize,
+}
+
+/// Tells the dispatcher how long the check is waiting and why, then waits
+async fn wait(check: &RunnableCheck, duration: Duration, reason: &str) {
+    let until = OffsetDateTime::now_utc() + duration;
+    check
+        .updates
+        .send(CheckStatus::Waiting(duration, until, reason.to_owned()), None);
+    sleep(duration).await;
 }
 
-pub async fn run_check_for_alerts(check: RunnableCheck) {
+pub async fn run_check_for_alerts(check: RunnableCheck, skipped: notify::SkippedChecks) {
     let policy: Policy = check.alert_policy.clone();
+    let key = check.spread_key;
+
+    // Scheduled checks first run when their schedule comes around, rather than at startup
+    let (startup_delay, reason) = match policy.schedule {
+        Some(_) => (policy.check_wait(key, 0), "first scheduled run"),
+        None => (policy.startup_delay(key), "startup splay"),
+    };
+    if !startup_delay.is_zero() {
+        wait(&check, startup_delay, reason).await;
+    }
+
+    let mut iteration = 0;
+    let mut next_wait = |rechecking: bool| {
+        iteration += 1;
+        if rechecking {
+            policy.jittered(policy.recheck_interval, key, iteration)
+        } else {
+            policy.check_wait(key, iteration)
+        }
+    };
 
     loop {
+        let now = OffsetDateTime::now_utc();
+        if let Some(until) = check.maintenance.skipped_until(now) {
+            // Waking for the next check anyway notices windows removed through the control API
+            let interval = next_wait(false);
+            let remaining = Duration::try_from(until - now).unwrap_or_default();
+            if remaining <= interval {
+                wait(&check, remaining, "end of maintenance window").await;
+            } else {
+                wait(&check, interval, "next check, during maintenance window").await;
+            }
+            continue;
+        }
+
+        if skipped
+            .lock()
+            .expect("skipped checks lock is not poisoned")
+            .contains(&check.checker.id())
+        {
+            wait(&check, next_wait(false), "end of Alertmanager silence").await;
+            continue;
+        }
+
+        // Once a check fails, keep rechecking until it has passed enough times in a row to resolve
+        let mut failed = false;
+        let mut successes = 0;
         loop {
             let result = run_check(check.clone()).await;
-            if !result.is_failure() {
-                break;
+            if result.is_failure() {
+                failed = true;
+                successes = 0;
+            } else {
+                successes += 1;
+                if !failed || successes >= policy.successes_before_resolve {
+                    break;
+                }
             }
 
-            check.updates.send(
-                CheckStatus::Waiting(policy.recheck_interval, "recheck".to_owned()),
-                None,
-            );
-            sleep(policy.recheck_interval).await;
+            wait(&check, next_wait(true), "recheck").await;
         }
-        check.updates.send(
-            CheckStatus::Waiting(policy.check_interval, "next check".to_owned()),
-            None,
-        );
-        sleep(policy.check_interval).await;
+
+        wait(&check, next_wait(false), "next check").await;
     }
 }
 
 pub fn run_alerts(
-    checks: Vec<RunnableCheck>,
-    registry: HashMap<usize, CheckInfo>,
+    mut check_set: reload::CheckSet,
+    loaded: reload::Loaded,
     rx: UnboundedReceiver<CheckUpdate>,
     cfg: Config,
 ) -> Result<()> {
-    let checks: FuturesUnordered<_> = checks.into_iter().map(run_check_for_alerts).collect();
-
     let rt = tokio::runtime::Builder::new_multi_thread()
         .enable_time()
         .enable_io()
         .worker_threads(4)
         .build()?;
 
-    let printer = rt.spawn(alertmanager::AlertManagerClient::new(cfg, registry, rx)?.run());
+    let registry = loaded
+        .checks
+        .iter()
+        .map(|check| (check.runnable.checker.id(), check.info.clone()))
+        .collect();
+    let (changes_tx, changes_rx) = unbounded_channel();
+
+    // Some notifiers start background tasks when constructed, as does the control API
+    let skipped = notify::SkippedChecks::default();
+    let dispatcher = {
+        let _guard = rt.enter();
+        if let Some(control_cfg) = cfg.control.clone() {
+            rt.spawn(control::serve(control_cfg, check_set.maintenance())?);
+        }
+        notify::Dispatcher::new(cfg, registry, skipped.clone(), rx, changes_rx)?
+    };
+
+    // Checks silenced upstream shouldn't run at all, not even once at startup
+    rt.block_on(dispatcher.sync_silences());
+    let dispatcher = rt.spawn(dispatcher.run());
+
+    // Checks aren't Send, so they run on this thread, where they can be started and stopped on reload
+    let local = LocalSet::new();
+    local.block_on(&rt, async move {
+        let mut running = reload::Running::new(loaded, changes_tx, skipped);
+        let mut hangup = signal(SignalKind::hangup())?;
+        let mut interrupt = signal(SignalKind::interrupt())?;
+        let mut terminate = signal(SignalKind::terminate())?;
 
-    rt.block_on(checks.count());
+        loop {
+            tokio::select! {
+                _ = interrupt.recv() => break,
+                _ = terminate.recv() => break,
+                _ = hangup.recv() => {}
+            }
+
+            if !check_set.reloadable() {
+                warn!("Received SIGHUP, but the config was read from stdin and can't be reloaded");
+                continue;
+            }
+
+            info!("Received SIGHUP, reloading config");
+            match check_set.load() {
+                Ok(loaded) => running.apply(loaded),
+                Err(err) => error!("Unable to reload config, keeping the current checks: {:#}", err),
+            }
+        }
+
+        info!("Stopping checks and delivering pending alerts");
+        Ok::<_, simple_eyre::Report>(())
+    })?;
+
+    // Dropping the checks closes the update channel, which stops the dispatcher once its notifiers are done
+    drop(local);
+    rt.block_on(dispatcher)?;
 
-    rt.block_on(printer)?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spread() {
+        let policy = Policy {
+            splay: Some(Duration::from_secs(60)),
+            jitter: 0.1,
+            ..Policy::default()
+        };
+
+        // The same check always gets the same delays, while different checks are spread out
+        assert_eq!(policy.startup_delay(3), policy.startup_delay(3));
+        let delays: Vec<Duration> = (0..20).map(|key| policy.startup_delay(key)).collect();
+        assert!(delays.iter().all(|delay| *delay < Duration::from_secs(60)));
+        assert!(delays.windows(2).any(|pair| pair[0] != pair[1]));
+
+        let interval = Duration::from_secs(100);
+        for iteration in 0..20 {
+            let wait = policy.jittered(interval, 3, iteration);
+            assert!(wait >= Duration::from_secs(90) && wait <= Duration::from_secs(110));
+            assert_eq!(wait, policy.jittered(interval, 3, iteration));
+        }
+
+        assert_eq!(Policy::default().jittered(interval, 3, 1), interval);
+        assert_eq!(policy.longest_wait(), Duration::from_secs(330));
+    }
+
+    #[test]
+    fn test_spread_key() {
+        // Pinned, since the spread has to be the same in every build, not just within one process
+        assert_eq!(spread_key(""), 0xcbf29ce484222325);
+        assert_eq!(spread_key("a"), 0xaf63dc4c8601ec8c);
+
+        let policy = Policy {
+            splay: Some(Duration::from_secs(60)),
+            ..Policy::default()
+        };
+        let identity = r#"{"labels":{},"params":{"url":"http://web-1/"},"type":"http"}#0"#;
+        assert_eq!(
+            policy.startup_delay(spread_key(identity)),
+            policy.startup_delay(spread_key(identity))
+        );
+        assert_ne!(
+            policy.startup_delay(spread_key(identity)),
+            policy.startup_delay(spread_key(&identity.replace("web-1", "web-2")))
+        );
+    }
+
+    #[test]
+    fn test_schedule() {
+        let policy: Policy = serde_json::from_value(serde_json::json!({
+            "schedule": "0 12 * * *",
+            "timezone": "Asia/Kolkata",
+            "recheckInterval": 60,
+        }))
+        .unwrap();
+
+        let wait = policy.check_wait(3, 1);
+        let next = OffsetDateTime::now_utc() + wait;
+        assert!(wait <= Duration::from_secs(24 * 60 * 60));
+        // Noon in Kolkata is 06:30 UTC
+        assert_eq!((next.hour(), next.minute()), (6, 30));
+        assert_eq!(policy.longest_wait(), Duration::from_secs(60));
+
+        for invalid in ["*/15 8-18 * * NOPE", "0 0 30 2 *"] {
+            let policy = serde_json::from_value::<Policy>(serde_json::json!({ "schedule": invalid }));
+            assert!(policy.is_err(), "{} should be rejected", invalid);
+        }
+        assert!(serde_json::from_value::<Policy>(serde_json::json!({ "timezone": "Mars/Olympus" })).is_err());
+    }
+
+    #[test]
+    fn test_legacy_base_url() {
+        let config: Config = serde_json::from_value(serde_json::json!({
+            "baseURL": "http://alertmanager:9093/api/v1/",
+            "realertInterval": 60,
+            "allowOutputAnnotation": false,
+        }))
+        .unwrap();
+
+        let notifiers = config.all_notifiers();
+        assert_eq!(notifiers.len(), 1);
+        match notifiers[0] {
+            notify::Config::Alertmanager(ref am_config) => {
+                assert_eq!(am_config.base_urls, vec!["http://alertmanager:9093".to_owned()]);
+            }
+            _ => panic!("baseURL should become an Alertmanager notifier"),
+        }
+    }
+}
@@ -1,14 +1,89 @@
 use std::collections::HashMap;
+use std::fs;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
 
-use log::{error, info};
+use async_trait::async_trait;
+use futures::future::join_all;
+use log::{error, warn};
+use regex::Regex;
+use reqwest::{Certificate, Identity, RequestBuilder};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use simple_eyre::eyre::{Result, WrapErr};
+use serde_with::{serde_as, DurationSeconds};
+use simple_eyre::eyre::{eyre, Result, WrapErr};
 use time::OffsetDateTime;
-use tokio::sync::mpsc::UnboundedReceiver;
-use tokio::time::{interval, MissedTickBehavior};
 
-use crate::alert::Config as AlertConfig;
-use crate::{CheckInfo, CheckStatus, CheckUpdate};
+use crate::maintenance::Mode;
+use crate::notify::{Alert, Notifier};
+use crate::{retry, template};
+
+#[derive(Clone, Deserialize, JsonSchema, Debug)]
+#[schemars(rename = "AlertmanagerAuth")]
+#[serde(rename_all = "camelCase")]
+pub enum Auth {
+    #[serde(rename_all = "camelCase")]
+    Basic { username: String, password_file: String },
+    #[serde(rename_all = "camelCase")]
+    Bearer { token_file: String },
+}
+
+fn default_timeout() -> Duration {
+    Duration::from_secs(10)
+}
+
+#[serde_as]
+#[derive(Clone, Deserialize, JsonSchema, Debug)]
+#[schemars(rename = "AlertmanagerConfig")]
+#[serde(rename_all = "camelCase")]
+pub struct Config {
+    /// Every Alertmanager in a cluster, all of which are sent every alert
+    #[serde(rename = "baseURLs")]
+    pub base_urls: Vec<String>,
+    #[serde(default)]
+    pub retry_policy: retry::Policy,
+    /// How many seconds to wait for each request to an Alertmanager before it counts as failed
+    #[serde_as(as = "DurationSeconds<f64>")]
+    #[schemars(with = "f64")]
+    #[serde(default = "default_timeout")]
+    pub timeout: Duration,
+    pub auth: Option<Auth>,
+    /// A PEM file with additional CA certificates to trust
+    pub ca_file: Option<String>,
+    /// PEM files with a client certificate and PKCS#8 key to present
+    pub client_cert_file: Option<String>,
+    pub client_key_file: Option<String>,
+    /// A template for each alert's link back to its source, e.g. `https://status.example.com/checks/{{ id }}`
+    #[serde(rename = "generatorURL")]
+    pub generator_url: Option<String>,
+    /// What to do with checks whose alerts would match an active silence; silences are ignored if omitted
+    pub respect_silences: Option<Mode>,
+}
+
+impl Config {
+    /// An Alertmanager given by the deprecated `alerting.baseURL`, which pointed at an API version rather than the
+    /// server itself
+    pub fn from_base_url(base_url: &str) -> Self {
+        let base_url = base_url.trim_end_matches('/');
+        let base_url = base_url
+            .strip_suffix("/api/v1")
+            .or_else(|| base_url.strip_suffix("/api/v2"))
+            .unwrap_or(base_url);
+
+        Config {
+            base_urls: vec![base_url.to_owned()],
+            retry_policy: retry::Policy::default(),
+            timeout: default_timeout(),
+            auth: None,
+            ca_file: None,
+            client_cert_file: None,
+            client_key_file: None,
+            generator_url: None,
+            respect_silences: None,
+        }
+    }
+}
 
 #[derive(Clone, Serialize, Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -27,108 +102,364 @@ pub struct PostableAlert {
     generator_url: Option<String>,
 }
 
+fn default_is_equal() -> bool {
+    true
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct GettableMatcher {
+    name: String,
+    value: String,
+    is_regex: bool,
+    #[serde(default = "default_is_equal")]
+    is_equal: bool,
+}
+
+#[derive(Deserialize, Debug)]
+struct SilenceStatus {
+    state: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct GettableSilence {
+    id: String,
+    matchers: Vec<GettableMatcher>,
+    status: SilenceStatus,
+}
+
+enum MatchValue {
+    Exact(String),
+    Regex(Regex),
+}
+
+struct Matcher {
+    name: String,
+    value: MatchValue,
+    is_equal: bool,
+}
+
+impl Matcher {
+    /// Matches the way Alertmanager does, treating missing labels as empty and anchoring regexes
+    fn matches(&self, labels: &HashMap<String, String>) -> bool {
+        let value = labels.get(&self.name).map(String::as_str).unwrap_or_default();
+        let matched = match self.value {
+            MatchValue::Exact(ref expected) => value == expected,
+            MatchValue::Regex(ref regex) => regex.is_match(value),
+        };
+
+        matched == self.is_equal
+    }
+}
+
+struct Silence {
+    matchers: Vec<Matcher>,
+}
+
+impl Silence {
+    fn from_gettable(silence: GettableSilence) -> Result<Self> {
+        let matchers = silence
+            .matchers
+            .into_iter()
+            .map(|matcher| {
+                let value = if matcher.is_regex {
+                    MatchValue::Regex(
+                        Regex::new(&format!("^(?:{})$", matcher.value))
+                            .wrap_err(format!("Invalid regex '{}' in silence {}", matcher.value, silence.id))?,
+                    )
+                } else {
+                    MatchValue::Exact(matcher.value)
+                };
+
+                Ok(Matcher {
+                    name: matcher.name,
+                    value,
+                    is_equal: matcher.is_equal,
+                })
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Silence { matchers })
+    }
+
+    fn matches(&self, labels: &HashMap<String, String>) -> bool {
+        self.matchers.iter().all(|matcher| matcher.matches(labels))
+    }
+}
+
+/// The active silences among those fetched, skipping any this can't match against rather than all of them
+fn active_silences(silences: Vec<GettableSilence>) -> Vec<Silence> {
+    silences
+        .into_iter()
+        .filter(|silence| silence.status.state == "active")
+        .filter_map(|silence| match Silence::from_gettable(silence) {
+            Ok(silence) => Some(silence),
+            Err(err) => {
+                warn!("Ignoring silence: {:#}", err);
+                None
+            }
+        })
+        .collect()
+}
+
+enum Credentials {
+    Basic(String, String),
+    Bearer(String),
+}
+
+fn read_secret(path: &str) -> Result<String> {
+    Ok(fs::read_to_string(path)
+        .wrap_err(format!("Unable to read secret from '{}'", path))?
+        .trim_end()
+        .to_owned())
+}
+
+struct Endpoint {
+    url: String,
+    silences_url: String,
+    failures: AtomicU64,
+}
+
 pub struct AlertManagerClient {
-    active_alerts: HashMap<usize, PostableAlert>,
-    alert_config: AlertConfig,
     client: reqwest::Client,
-    registry: HashMap<usize, CheckInfo>,
-    updates: UnboundedReceiver<CheckUpdate>,
-    url: String,
+    credentials: Option<Credentials>,
+    endpoints: Vec<Endpoint>,
+    generator_url: Option<String>,
+    respect_silences: Option<Mode>,
+    retry_policy: retry::Policy,
+    /// Active silences as of the last sync
+    silences: Mutex<Vec<Silence>>,
 }
 
 impl AlertManagerClient {
-    pub fn new(
-        alert_config: AlertConfig,
-        registry: HashMap<usize, CheckInfo>,
-        updates: UnboundedReceiver<CheckUpdate>,
-    ) -> Result<Self> {
+    pub fn new(config: Config) -> Result<Self> {
+        if config.base_urls.is_empty() {
+            return Err(eyre!("At least one Alertmanager URL is required"));
+        }
+
+        let mut builder = reqwest::ClientBuilder::new().timeout(config.timeout);
+
+        if let Some(ref ca_file) = config.ca_file {
+            let pem = fs::read(ca_file).wrap_err(format!("Unable to read CA file '{}'", ca_file))?;
+            builder = builder.add_root_certificate(
+                Certificate::from_pem(&pem).wrap_err(format!("Invalid CA certificate in '{}'", ca_file))?,
+            );
+        }
+
+        match (config.client_cert_file, config.client_key_file) {
+            (Some(cert_file), Some(key_file)) => {
+                let cert = fs::read(&cert_file).wrap_err(format!("Unable to read client cert '{}'", cert_file))?;
+                let key = fs::read(&key_file).wrap_err(format!("Unable to read client key '{}'", key_file))?;
+                builder =
+                    builder.identity(Identity::from_pkcs8_pem(&cert, &key).wrap_err("Invalid client certificate")?);
+            }
+            (None, None) => {}
+            _ => return Err(eyre!("clientCertFile and clientKeyFile must be given together")),
+        }
+
+        let credentials = match config.auth {
+            Some(Auth::Basic {
+                username,
+                password_file,
+            }) => Some(Credentials::Basic(username, read_secret(&password_file)?)),
+            Some(Auth::Bearer { token_file }) => Some(Credentials::Bearer(read_secret(&token_file)?)),
+            None => None,
+        };
+
         Ok(AlertManagerClient {
-            active_alerts: HashMap::new(),
-            // having url out of order avoids a copy
-            url: format!("{}/alerts", &alert_config.base_url),
-            alert_config,
-            client: reqwest::ClientBuilder::new()
-                .build()
-                .wrap_err("Unable to construct http client")?,
-            registry,
-            updates,
+            client: builder.build().wrap_err("Unable to construct http client")?,
+            credentials,
+            endpoints: config
+                .base_urls
+                .iter()
+                .map(|base_url| Endpoint {
+                    url: format!("{}/api/v2/alerts", base_url.trim_end_matches('/')),
+                    silences_url: format!("{}/api/v2/silences", base_url.trim_end_matches('/')),
+                    failures: AtomicU64::new(0),
+                })
+                .collect(),
+            generator_url: config.generator_url,
+            respect_silences: config.respect_silences,
+            retry_policy: config.retry_policy,
+            silences: Mutex::new(Vec::new()),
         })
     }
 
-    async fn process_update(&mut self, update: CheckUpdate) {
-        match update.status {
-            CheckStatus::Failed => {
-                // The await doesn't really work with entry or_insert
-                #[allow(clippy::map_entry)]
-                if !self.active_alerts.contains_key(&update.id) {
-                    if let Some(info) = self.registry.get(&update.id) {
-                        let mut alert = PostableAlert {
-                            starts_at: Some(time::OffsetDateTime::now_utc()),
-                            ends_at: None,
-                            labels: info.labels.clone(),
-                            annotations: info.annotations.clone(),
-                            generator_url: None,
-                        };
-
-                        if self.alert_config.allow_output_annotation {
-                            // Combining these ifs is an unstable feature
-                            if let Some(ref output) = update.msg {
-                                alert.annotations.insert("output".to_owned(), output.clone());
-                            };
-                        }
-
-                        info!("Check failed - {}", info.name);
-                        self.active_alerts.insert(update.id, alert);
-                        self.send_alerts().await;
-                    } else {
-                        error!(
-                            "Tried to send an alert for id {}, which was not in the registry; skipping transmission",
-                            update.id
-                        );
-                    }
-                }
-            }
-            CheckStatus::Succeeded => {
-                if let Some(alert) = self.active_alerts.get_mut(&update.id) {
-                    alert.ends_at = Some(time::OffsetDateTime::now_utc());
-                    info!("Check passing again: {:?}", alert.labels);
+    fn postable(&self, alert: &Alert) -> PostableAlert {
+        PostableAlert {
+            starts_at: Some(alert.starts_at),
+            ends_at: alert.ends_at,
+            labels: alert.labels.clone(),
+            annotations: alert.annotations.clone(),
+            generator_url: self
+                .generator_url
+                .as_ref()
+                .map(|template| template::render(template, |key| alert.lookup(key), template::url_escape)),
+        }
+    }
 
-                    self.send_alerts().await;
-                    self.active_alerts.remove(&update.id);
-                }
-            }
-            _ => {}
+    fn authorize(&self, request: RequestBuilder) -> RequestBuilder {
+        match self.credentials {
+            Some(Credentials::Basic(ref username, ref password)) => request.basic_auth(username, Some(password)),
+            Some(Credentials::Bearer(ref token)) => request.bearer_auth(token),
+            None => request,
         }
     }
 
-    async fn send_alerts(&self) {
-        let alerts: Vec<&PostableAlert> = self.active_alerts.values().collect();
-        if let Err(e) = self.client.post(&self.url).json(&alerts).send().await {
-            error!("Failure sending alerts: {}", e);
+    async fn post(&self, url: &str, alerts: &[PostableAlert]) -> Result<()> {
+        let response = self
+            .authorize(self.client.post(url))
+            .json(&alerts)
+            .send()
+            .await
+            .wrap_err("Error making request")?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            return Err(eyre!("Received HTTP error '{}': {}", status, body));
         }
+
+        Ok(())
+    }
+
+    async fn get_silences(&self, url: &str) -> Result<Vec<Silence>> {
+        let response = self
+            .authorize(self.client.get(url))
+            .send()
+            .await
+            .wrap_err("Error making request")?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            return Err(eyre!("Received HTTP error '{}': {}", status, body));
+        }
+
+        let silences: Vec<GettableSilence> = response.json().await.wrap_err("Invalid silences response")?;
+        Ok(active_silences(silences))
     }
 
-    pub async fn run(mut self) {
-        let mut interval = interval(self.alert_config.realert_interval);
-        interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    async fn send_to(&self, endpoint: &Endpoint, alerts: &[PostableAlert]) {
+        let mut retrier = retry::Retrier::new(self.retry_policy.clone());
 
         loop {
-            tokio::select! {
-                _ = interval.tick() => {
-                    if !self.active_alerts.is_empty() {
-                        self.send_alerts().await;
-                    }
-                }
-                update = self.updates.recv() => {
-                    match update {
-                        Some(update) => self.process_update(update).await,
-                        None => {
-                            self.send_alerts().await;
-                            return
-                        }
-                    }
+            let err = match self.post(&endpoint.url, alerts).await {
+                Ok(_) => return,
+                Err(err) => err,
+            };
+
+            if retrier.retry().await.is_none() {
+                let failures = endpoint.failures.fetch_add(1, Ordering::Relaxed) + 1;
+                error!(
+                    "Failure sending alerts to {} ({} delivery failures so far): {:#}",
+                    endpoint.url, failures, err
+                );
+                return;
+            }
+        }
+    }
+
+    async fn send_alerts(&self, alerts: &[PostableAlert]) {
+        join_all(self.endpoints.iter().map(|endpoint| self.send_to(endpoint, alerts))).await;
+    }
+}
+
+#[async_trait]
+impl Notifier for AlertManagerClient {
+    fn name(&self) -> String {
+        let urls: Vec<&str> = self.endpoints.iter().map(|endpoint| endpoint.url.as_str()).collect();
+        format!("alertmanager {}", urls.join(", "))
+    }
+
+    async fn notify(&self, alert: &Alert) {
+        self.send_alerts(&[self.postable(alert)]).await;
+    }
+
+    async fn refresh(&self, alerts: &[&Alert]) {
+        let alerts: Vec<PostableAlert> = alerts.iter().map(|alert| self.postable(alert)).collect();
+        self.send_alerts(&alerts).await;
+    }
+
+    async fn sync_silences(&self) {
+        if self.respect_silences.is_none() {
+            return;
+        }
+
+        // Silences are shared across a cluster, so the first Alertmanager to answer is enough
+        for endpoint in self.endpoints.iter() {
+            match self.get_silences(&endpoint.silences_url).await {
+                Ok(silences) => {
+                    *self.silences.lock().expect("silences lock is not poisoned") = silences;
+                    return;
                 }
+                Err(err) => error!("Unable to fetch silences from {}: {:#}", endpoint.silences_url, err),
             }
         }
     }
+
+    fn silenced(&self, labels: &HashMap<String, String>) -> Option<Mode> {
+        let mode = self.respect_silences?;
+        let silences = self.silences.lock().expect("silences lock is not poisoned");
+        silences.iter().any(|silence| silence.matches(labels)).then_some(mode)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gettable(state: &str, matchers: &[(&str, &str, bool, bool)]) -> GettableSilence {
+        GettableSilence {
+            id: "test".to_owned(),
+            matchers: matchers
+                .iter()
+                .map(|(name, value, is_regex, is_equal)| GettableMatcher {
+                    name: name.to_string(),
+                    value: value.to_string(),
+                    is_regex: *is_regex,
+                    is_equal: *is_equal,
+                })
+                .collect(),
+            status: SilenceStatus {
+                state: state.to_owned(),
+            },
+        }
+    }
+
+    fn silence(matchers: &[(&str, &str, bool, bool)]) -> Silence {
+        Silence::from_gettable(gettable("active", matchers)).unwrap()
+    }
+
+    #[test]
+    fn test_silence_matches() {
+        let labels = HashMap::from([
+            ("alertname".to_owned(), "HealthCheckFailed".to_owned()),
+            ("hostname".to_owned(), "web-1".to_owned()),
+        ]);
+
+        assert!(silence(&[("hostname", "web-1", false, true)]).matches(&labels));
+        assert!(silence(&[("hostname", "web-.*", true, true)]).matches(&labels));
+        // regexes are anchored
+        assert!(!silence(&[("hostname", "web", true, true)]).matches(&labels));
+        assert!(!silence(&[("hostname", "web-1", false, false)]).matches(&labels));
+        // missing labels match as empty
+        assert!(silence(&[("env", "", false, true)]).matches(&labels));
+        assert!(!silence(&[("hostname", "web-1", false, true), ("env", "prod", false, true)]).matches(&labels));
+    }
+
+    #[test]
+    fn test_invalid_silence_is_skipped() {
+        let labels = HashMap::from([("hostname".to_owned(), "web-1".to_owned())]);
+
+        // Alertmanager accepts Go regex syntax which the regex crate doesn't
+        let silences = active_silences(vec![
+            gettable("active", &[("hostname", "\\Qweb-1\\E", true, true)]),
+            gettable("expired", &[("hostname", "web-1", false, true)]),
+            gettable("active", &[("hostname", "web-.*", true, true)]),
+        ]);
+
+        assert_eq!(silences.len(), 1);
+        assert!(silences[0].matches(&labels));
+    }
 }
@@ -1,29 +1,85 @@
+use std::path::Path;
 use std::time::Duration;
 use std::{collections::HashMap, rc::Rc};
 
-use serde::Deserialize;
+use clap::ValueEnum;
+use schemars::gen::SchemaSettings;
+use schemars::schema::{RootSchema, Schema, SchemaObject};
+use schemars::visit::{self, Visitor};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
 use serde_with::{serde_as, DurationSeconds};
 
-use simple_eyre::eyre::Result;
+use simple_eyre::eyre::{Result, WrapErr};
 
-use crate::{alert, dns, http, retry, ssh, Checker as CheckerTrait};
+use crate::{alert, dns, http, limit, maintenance, retry, sanitize, ssh, Checker as CheckerTrait};
+
+fn default_check_timeout() -> Duration {
+    Duration::from_secs(10)
+}
 
 #[serde_as]
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, JsonSchema, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct CheckDefinition {
+    /// How failed runs of the check are retried before it's considered failed
+    #[serde(default)]
     pub retry_policy: retry::Policy,
+    /// The number of seconds before each run of the check times out
     #[serde_as(as = "DurationSeconds<f64>")]
+    #[schemars(with = "f64")]
+    #[serde(default = "default_check_timeout")]
     pub check_timeout: Duration,
+    /// Successful checks slower than this are reported as warnings
+    #[serde_as(as = "Option<DurationSeconds<f64>>")]
+    #[schemars(with = "Option<f64>")]
+    #[serde(default)]
+    pub warn_after: Option<Duration>,
+    /// Checks slower than this are reported as failures, even if they complete within `checkTimeout`
+    #[serde_as(as = "Option<DurationSeconds<f64>>")]
+    #[schemars(with = "Option<f64>")]
+    #[serde(default)]
+    pub fail_after: Option<Duration>,
+    /// Arbitrary key/value data describing the check, used to select checks and as alert labels
+    #[serde(default)]
     pub labels: HashMap<String, String>,
+    /// Extra annotations for the check's alerts
+    #[serde(default)]
     pub annotations: HashMap<String, String>,
+    /// How the check runs in alerting mode
+    #[serde(default)]
     pub alert_policy: alert::Policy,
 
     #[serde(flatten)]
     pub config: CheckConfig,
 }
 
-#[derive(Clone, Deserialize, Debug)]
+impl CheckDefinition {
+    /// What identifies the check across reloads and files: its type, parameters and labels
+    pub fn identity(&self) -> Result<String> {
+        Ok(serde_json::json!({
+            "type": self.config.check_type(),
+            "params": self.config.params()?,
+            "labels": self.labels,
+        })
+        .to_string())
+    }
+}
+
+/// Every key a check definition may have, since the flattened `CheckConfig` hides unknown keys from serde
+pub const CHECK_DEFINITION_FIELDS: &[&str] = &[
+    "type",
+    "params",
+    "retryPolicy",
+    "checkTimeout",
+    "warnAfter",
+    "failAfter",
+    "labels",
+    "annotations",
+    "alertPolicy",
+];
+
+#[derive(Clone, Deserialize, JsonSchema, Serialize, Debug)]
 #[serde(tag = "type", content = "params", rename_all = "lowercase")]
 pub enum CheckConfig {
     Http(http::Config),
@@ -32,6 +88,23 @@ pub enum CheckConfig {
 }
 
 impl CheckConfig {
+    pub fn check_type(&self) -> &'static str {
+        match self {
+            CheckConfig::Http(_) => "http",
+            CheckConfig::Dns(_) => "dns",
+            CheckConfig::Ssh(_) => "ssh",
+        }
+    }
+
+    /// The check's parameters as JSON, for use in templates
+    pub fn params(&self) -> Result<serde_json::Value> {
+        Ok(match self {
+            CheckConfig::Http(http_config) => serde_json::to_value(http_config)?,
+            CheckConfig::Dns(dns_config) => serde_json::to_value(dns_config)?,
+            CheckConfig::Ssh(ssh_config) => serde_json::to_value(ssh_config)?,
+        })
+    }
+
     pub fn into_check(self, id: usize) -> Result<Rc<dyn CheckerTrait>> {
         Ok(match self {
             CheckConfig::Http(http_config) => Rc::new(http::Checker::new(id, http_config)?),
@@ -41,8 +114,154 @@ impl CheckConfig {
     }
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, JsonSchema, Debug)]
 pub struct Config {
     pub alerting: Option<alert::Config>,
     pub checks: Vec<CheckDefinition>,
+    #[serde(default)]
+    pub output: sanitize::Config,
+    #[serde(default)]
+    pub maintenance: Vec<maintenance::Config>,
+    #[serde(default)]
+    pub concurrency: limit::Config,
+}
+
+/// The formats config files can be written in
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum Format {
+    Json,
+    Yaml,
+    Toml,
+}
+
+impl Format {
+    /// Guesses the format of a config file from its extension, defaulting to JSON
+    pub fn from_path(path: &str) -> Format {
+        match Path::new(path).extension().and_then(|ext| ext.to_str()) {
+            Some("yaml" | "yml") => Format::Yaml,
+            Some("toml") => Format::Toml,
+            _ => Format::Json,
+        }
+    }
+
+    /// Parses a config into JSON, which everything else works with
+    pub fn parse(&self, data: &str) -> Result<serde_json::Value> {
+        Ok(match self {
+            Format::Json => serde_json::from_str(data).wrap_err("Invalid JSON")?,
+            Format::Yaml => serde_yaml::from_str(data).wrap_err("Invalid YAML")?,
+            Format::Toml => toml::from_str(data).wrap_err("Invalid TOML")?,
+        })
+    }
+}
+
+/// Forbids keys a schema doesn't describe, which loading the config reports as unknown fields
+#[derive(Clone, Debug)]
+struct DenyUnknownFields;
+
+impl Visitor for DenyUnknownFields {
+    fn visit_schema_object(&mut self, schema: &mut SchemaObject) {
+        visit::visit_schema_object(self, schema);
+
+        // Maps already describe their values, and other schemas aren't objects
+        let Some(ref mut object) = schema.object else {
+            return;
+        };
+        if object.additional_properties.is_some() || object.properties.is_empty() {
+            return;
+        }
+
+        let deny = || Some(Box::new(Schema::Bool(false)));
+        match schema
+            .subschemas
+            .as_mut()
+            .and_then(|subschemas| subschemas.one_of.as_mut())
+        {
+            // A flattened enum, whose variants have to allow the surrounding keys, since subschemas can't see each other's
+            Some(variants) => {
+                for variant in variants {
+                    if let Schema::Object(variant) = variant {
+                        let variant = variant.object();
+                        for key in object.properties.keys() {
+                            variant.properties.entry(key.clone()).or_insert(Schema::Bool(true));
+                        }
+                        variant.additional_properties = deny();
+                    }
+                }
+            }
+            None => object.additional_properties = deny(),
+        }
+    }
+}
+
+/// A JSON Schema describing the config file format
+pub fn schema() -> RootSchema {
+    SchemaSettings::draft07()
+        .with_visitor(DenyUnknownFields)
+        .into_generator()
+        .into_root_schema_for::<Config>()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_formats() {
+        let expected = serde_json::json!({ "checks": [{ "type": "dns", "checkTimeout": 5 }] });
+
+        assert_eq!(Format::from_path("checks.yml"), Format::Yaml);
+        assert_eq!(Format::from_path("checks.d/web.toml"), Format::Toml);
+        assert_eq!(Format::from_path("-"), Format::Json);
+
+        assert_eq!(
+            Format::Yaml
+                .parse("checks:\n  - type: dns\n    checkTimeout: 5\n")
+                .unwrap(),
+            expected
+        );
+        assert_eq!(
+            Format::Toml
+                .parse("[[checks]]\ntype = \"dns\"\ncheckTimeout = 5\n")
+                .unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_schema_names() {
+        // Types sharing a name, like every module's `Config`, need a schemars rename to be told apart
+        let schema = schema();
+        let ambiguous: Vec<&String> = schema
+            .definitions
+            .keys()
+            .filter(|name| name.ends_with(|c: char| c.is_ascii_digit()))
+            .collect();
+
+        assert!(ambiguous.is_empty(), "ambiguous schema names: {:?}", ambiguous);
+    }
+
+    #[test]
+    fn test_schema_denies_unknown_fields() {
+        let schema = serde_json::to_value(schema()).unwrap();
+        let definitions = &schema["definitions"];
+
+        // Typos like `checkTimout` at the top level of a check, or `insecure` in its params
+        assert_eq!(schema["additionalProperties"], false);
+        assert_eq!(definitions["HttpConfig"]["additionalProperties"], false);
+        assert_eq!(definitions["AlertPolicy"]["additionalProperties"], false);
+        for variant in definitions["CheckDefinition"]["oneOf"].as_array().unwrap() {
+            assert_eq!(variant["additionalProperties"], false);
+            assert_eq!(variant["properties"]["checkTimeout"], true);
+        }
+        assert!(definitions["CheckDefinition"].get("additionalProperties").is_none());
+        for variant in definitions["MaintenanceWindow"]["oneOf"].as_array().unwrap() {
+            assert_eq!(variant["properties"]["select"], true);
+        }
+
+        // Maps of labels still allow any key
+        assert_eq!(
+            definitions["CheckDefinition"]["properties"]["labels"]["additionalProperties"],
+            serde_json::json!({ "type": "string" })
+        );
+    }
 }
@@ -0,0 +1,145 @@
+use std::convert::Infallible;
+use std::future::Future;
+use std::net::SocketAddr;
+
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use log::{error, info};
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use simple_eyre::eyre::{Result, WrapErr};
+
+use crate::maintenance;
+
+/// An HTTP API for changing a running instance, which has no authentication of its own
+#[derive(Clone, Deserialize, JsonSchema, Debug)]
+#[schemars(rename = "ControlConfig")]
+#[serde(rename_all = "camelCase")]
+pub struct Config {
+    /// The address to listen on, e.g. `127.0.0.1:9110`
+    #[schemars(with = "String")]
+    pub listen: SocketAddr,
+}
+
+fn respond(status: StatusCode, body: Value) -> Response<Body> {
+    let mut response = Response::new(Body::from(body.to_string()));
+    *response.status_mut() = status;
+    response
+        .headers_mut()
+        .insert("Content-Type", "application/json".parse().expect("valid header value"));
+    response
+}
+
+fn error(status: StatusCode, message: String) -> Response<Body> {
+    respond(status, json!({ "error": message }))
+}
+
+async fn add_window(request: Request<Body>, maintenance: &maintenance::Maintenance) -> Response<Body> {
+    let added = match hyper::body::to_bytes(request.into_body()).await {
+        Ok(body) => serde_json::from_slice(&body)
+            .wrap_err("Invalid JSON")
+            .and_then(|definition| maintenance.add(definition)),
+        Err(err) => return error(StatusCode::BAD_REQUEST, format!("Unable to read request: {}", err)),
+    };
+
+    match added {
+        Ok(id) => {
+            info!("Added maintenance window {} through the control API", id);
+            respond(StatusCode::CREATED, json!({ "id": id }))
+        }
+        Err(err) => error(StatusCode::BAD_REQUEST, format!("{:#}", err)),
+    }
+}
+
+async fn handle(request: Request<Body>, maintenance: maintenance::Maintenance) -> Result<Response<Body>, Infallible> {
+    let path = request.uri().path().trim_end_matches('/').to_owned();
+
+    let response = match (request.method(), path.as_str()) {
+        (&Method::GET, "/maintenance") => respond(StatusCode::OK, Value::Array(maintenance.list())),
+        (&Method::POST, "/maintenance") => add_window(request, &maintenance).await,
+        (&Method::DELETE, path) if path.starts_with("/maintenance/") => match path["/maintenance/".len()..].parse() {
+            Ok(id) if maintenance.remove(id) => {
+                info!("Removed maintenance window {} through the control API", id);
+                respond(StatusCode::OK, json!({ "id": id }))
+            }
+            _ => error(StatusCode::NOT_FOUND, format!("No maintenance window at {}", path)),
+        },
+        _ => error(
+            StatusCode::NOT_FOUND,
+            format!("No route for {} {}", request.method(), path),
+        ),
+    };
+
+    Ok(response)
+}
+
+/// Binds the control API's address, returning the server to run
+///
+/// This must be called within a tokio runtime.
+pub fn serve(config: Config, maintenance: maintenance::Maintenance) -> Result<impl Future<Output = ()>> {
+    let make_service = make_service_fn(move |_| {
+        let maintenance = maintenance.clone();
+        async move { Ok::<_, Infallible>(service_fn(move |request| handle(request, maintenance.clone()))) }
+    });
+
+    let server = Server::try_bind(&config.listen)
+        .wrap_err(format!("Unable to listen on {} for the control API", config.listen))?
+        .serve(make_service);
+    info!("Serving the control API on {}", config.listen);
+
+    Ok(async move {
+        if let Err(err) = server.await {
+            error!("Control API stopped: {}", err);
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn request(maintenance: &maintenance::Maintenance, method: Method, path: &str, body: &str) -> (u16, Value) {
+        let request = Request::builder()
+            .method(method)
+            .uri(path)
+            .body(Body::from(body.to_owned()))
+            .unwrap();
+        let response = handle(request, maintenance.clone()).await.unwrap();
+        let status = response.status().as_u16();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_maintenance_api() {
+        let maintenance = maintenance::Maintenance::default();
+        let window = r#"{ "select": "hostname:web-1", "mode": "skip",
+            "once": { "start": "2024-03-01T10:00:00Z", "end": "2024-03-01T12:00:00Z" } }"#;
+
+        let (status, body) = request(&maintenance, Method::POST, "/maintenance", window).await;
+        assert_eq!(status, 201);
+        let id = body["id"].as_u64().unwrap();
+
+        let (status, body) = request(&maintenance, Method::GET, "/maintenance", "").await;
+        assert_eq!(status, 200);
+        assert_eq!(body[0]["id"], id);
+        assert_eq!(body[0]["select"], "hostname:web-1");
+
+        let (status, body) = request(&maintenance, Method::POST, "/maintenance", r#"{ "mode": "skip" }"#).await;
+        assert_eq!(status, 400);
+        assert!(body["error"]
+            .as_str()
+            .unwrap()
+            .starts_with("Invalid maintenance window"));
+
+        let path = format!("/maintenance/{}", id);
+        assert_eq!(request(&maintenance, Method::DELETE, &path, "").await.0, 200);
+        assert_eq!(request(&maintenance, Method::DELETE, &path, "").await.0, 404);
+        assert_eq!(
+            request(&maintenance, Method::GET, "/maintenance", "").await.1,
+            json!([])
+        );
+    }
+}
@@ -1,12 +1,15 @@
 use async_trait::async_trait;
-use serde::Deserialize;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
 use simple_eyre::eyre::{Result, WrapErr};
 use trust_dns_resolver::TokioAsyncResolver;
 
-use crate::{Checker as CheckerTrait, UpdateChan};
+use crate::{Checker as CheckerTrait, Health, UpdateChan};
 
-#[derive(Clone, Default, Deserialize, Debug)]
+#[derive(Clone, Default, Deserialize, JsonSchema, Serialize, Debug)]
+#[schemars(rename = "DnsConfig")]
 pub struct Config {
+    /// The domain which has to resolve
     domain: String,
     // TODO add record type, possibly expected result
 }
@@ -35,9 +38,13 @@ impl CheckerTrait for Checker {
         format!("dns {}", self.config.domain)
     }
 
-    async fn check(&self, _updates: &UpdateChan) -> Result<()> {
+    fn instance(&self) -> String {
+        self.config.domain.clone()
+    }
+
+    async fn check(&self, _updates: &UpdateChan) -> Result<Health> {
         self.resolver.lookup_ip(self.config.domain.clone()).await?;
 
-        Ok(())
+        Ok(Health::Healthy)
     }
 }
@@ -0,0 +1,292 @@
+use std::fmt::Write;
+use std::fs;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use log::error;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde_with::{serde_as, DurationSeconds};
+use simple_eyre::eyre::{eyre, Result, WrapErr};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
+
+use crate::notify::{Alert, Notifier as NotifierTrait};
+
+fn default_port() -> u16 {
+    587
+}
+
+fn default_starttls() -> bool {
+    true
+}
+
+fn default_batch_window() -> Duration {
+    Duration::from_secs(30)
+}
+
+#[serde_as]
+#[derive(Clone, Deserialize, JsonSchema, Debug)]
+#[schemars(rename = "EmailConfig")]
+#[serde(rename_all = "camelCase")]
+pub struct Config {
+    relay: String,
+    #[serde(default = "default_port")]
+    port: u16,
+    /// Disabling STARTTLS is only intended for local testing
+    #[serde(default = "default_starttls")]
+    starttls: bool,
+    username: Option<String>,
+    /// A file containing the SMTP password, read at startup
+    password_file: Option<String>,
+    from: String,
+    to: Vec<String>,
+    /// Alerts changing within this window of the first change are sent together
+    #[serde_as(as = "DurationSeconds<f64>")]
+    #[schemars(with = "f64")]
+    #[serde(default = "default_batch_window")]
+    batch_window: Duration,
+}
+
+struct Mailer {
+    from: Mailbox,
+    to: Vec<Mailbox>,
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+}
+
+impl Mailer {
+    async fn send(&self, alerts: &[Alert]) -> Result<()> {
+        let firing = alerts.iter().filter(|alert| !alert.is_resolved()).count();
+        let subject = format!("[colmena-health] {} firing, {} resolved", firing, alerts.len() - firing);
+
+        let mut body = String::new();
+        for alert in alerts {
+            let status = alert.lookup("status").unwrap_or_default();
+            writeln!(body, "[{}] {}", status.to_uppercase(), alert.name)?;
+            writeln!(body, "  started: {}", alert.lookup("startsAt").unwrap_or_default())?;
+            if let Some(ends_at) = alert.lookup("endsAt") {
+                writeln!(body, "  ended: {}", ends_at)?;
+            }
+
+            let mut labels: Vec<_> = alert.labels.iter().collect();
+            labels.sort();
+            for (name, value) in labels {
+                writeln!(body, "  {}: {}", name, value)?;
+            }
+
+            let mut annotations: Vec<_> = alert.annotations.iter().collect();
+            annotations.sort();
+            for (name, value) in annotations {
+                writeln!(body, "  {}:", name)?;
+                for line in value.lines() {
+                    writeln!(body, "    {}", line)?;
+                }
+            }
+
+            body.push('\n');
+        }
+
+        let mut message = Message::builder().from(self.from.clone()).subject(subject);
+        for to in self.to.iter() {
+            message = message.to(to.clone());
+        }
+
+        self.transport
+            .send(message.body(body).wrap_err("Unable to build email")?)
+            .await
+            .wrap_err("Unable to send email")?;
+
+        Ok(())
+    }
+
+    async fn run(self, batch_window: Duration, mut alerts: UnboundedReceiver<Alert>) {
+        while let Some(first) = alerts.recv().await {
+            let mut batch = vec![first];
+
+            let _ = timeout(batch_window, async {
+                while let Some(alert) = alerts.recv().await {
+                    batch.push(alert);
+                }
+            })
+            .await;
+
+            if let Err(e) = self.send(&batch).await {
+                error!("Failure sending alert email: {:#}", e);
+            }
+        }
+    }
+}
+
+pub struct Notifier {
+    /// Taken on shutdown, which ends the mailer's current batch early
+    alerts: Mutex<Option<UnboundedSender<Alert>>>,
+    mailer: Mutex<Option<JoinHandle<()>>>,
+    relay: String,
+}
+
+impl Notifier {
+    /// Starts the background task which batches and sends mail, so this must be called within a tokio runtime
+    pub fn new(config: Config) -> Result<Self> {
+        let mut builder = if config.starttls {
+            AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&config.relay)
+                .wrap_err(format!("Unable to configure SMTP relay '{}'", config.relay))?
+        } else {
+            AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&config.relay)
+        }
+        .port(config.port);
+
+        match (config.username, config.password_file) {
+            (Some(username), Some(password_file)) => {
+                let password = fs::read_to_string(&password_file)
+                    .wrap_err(format!("Unable to read SMTP password from '{}'", password_file))?;
+                builder = builder.credentials(Credentials::new(username, password.trim_end().to_owned()));
+            }
+            (None, None) => {}
+            _ => return Err(eyre!("SMTP username and passwordFile must be given together")),
+        }
+
+        let mailer = Mailer {
+            from: config
+                .from
+                .parse()
+                .wrap_err(format!("Invalid from address '{}'", config.from))?,
+            to: config
+                .to
+                .iter()
+                .map(|to| to.parse().wrap_err(format!("Invalid to address '{}'", to)))
+                .collect::<Result<_>>()?,
+            transport: builder.build(),
+        };
+
+        let (tx, rx) = unbounded_channel();
+        let handle = tokio::spawn(mailer.run(config.batch_window, rx));
+
+        Ok(Notifier {
+            alerts: Mutex::new(Some(tx)),
+            mailer: Mutex::new(Some(handle)),
+            relay: config.relay,
+        })
+    }
+}
+
+#[async_trait]
+impl NotifierTrait for Notifier {
+    fn name(&self) -> String {
+        format!("email via {}", self.relay)
+    }
+
+    async fn notify(&self, alert: &Alert) {
+        let alerts = self.alerts.lock().expect("email queue lock is not poisoned");
+        let queued = alerts.as_ref().map(|alerts| alerts.send(alert.clone()).is_ok());
+        if queued != Some(true) {
+            error!("Unable to queue alert for {}", self.name());
+        }
+    }
+
+    /// Sends the batch being collected straight away, rather than dropping it
+    async fn shutdown(&self) {
+        self.alerts.lock().expect("email queue lock is not poisoned").take();
+
+        let mailer = self.mailer.lock().expect("email mailer lock is not poisoned").take();
+        if let Some(mailer) = mailer {
+            if let Err(e) = mailer.await {
+                error!("Email sender for {} stopped unexpectedly: {}", self.relay, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    /// Accepts SMTP connections, answering just enough of the protocol to collect each message's data
+    async fn smtp_sink(listener: TcpListener, messages: UnboundedSender<String>) {
+        while let Ok((stream, _)) = listener.accept().await {
+            let (read, mut write) = stream.into_split();
+            let mut lines = BufReader::new(read).lines();
+            write.write_all(b"220 sink ESMTP\r\n").await.unwrap();
+
+            let mut data: Option<String> = None;
+            while let Ok(Some(line)) = lines.next_line().await {
+                let reply: &[u8] = match data {
+                    Some(ref mut message) if line != "." => {
+                        message.push_str(&line);
+                        message.push('\n');
+                        continue;
+                    }
+                    Some(_) => {
+                        messages.send(data.take().unwrap()).unwrap();
+                        b"250 queued\r\n"
+                    }
+                    None if line.starts_with("DATA") => {
+                        data = Some(String::new());
+                        b"354 go ahead\r\n"
+                    }
+                    None if line.starts_with("QUIT") => b"221 bye\r\n",
+                    None => b"250 ok\r\n",
+                };
+                write.write_all(reply).await.unwrap();
+            }
+        }
+    }
+
+    fn alert(name: &str) -> Alert {
+        Alert {
+            id: Some(1),
+            name: name.to_owned(),
+            starts_at: time::OffsetDateTime::now_utc(),
+            ends_at: None,
+            labels: HashMap::from([("hostname".to_owned(), "web-1".to_owned())]),
+            annotations: HashMap::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_batches_and_flushes_on_shutdown() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (tx, mut messages) = unbounded_channel();
+        tokio::spawn(smtp_sink(listener, tx));
+
+        let config: Config = serde_json::from_value(serde_json::json!({
+            "relay": "127.0.0.1",
+            "port": port,
+            "starttls": false,
+            "from": "colmena-health@example.com",
+            "to": ["ops@example.com"],
+            "batchWindow": 3600,
+        }))
+        .unwrap();
+        let notifier = Notifier::new(config).unwrap();
+
+        let mut resolved = alert("dns example.com");
+        resolved.ends_at = Some(time::OffsetDateTime::now_utc());
+        notifier.notify(&alert("http https://web-1/health")).await;
+        notifier.notify(&resolved).await;
+
+        // The batch window is far off, so only shutting down sends the batch
+        notifier.shutdown().await;
+
+        let message = messages.recv().await.unwrap();
+        assert!(message.contains("Subject: [colmena-health] 1 firing, 1 resolved"));
+        assert!(message.contains("[FIRING] http https://web-1/health"));
+        assert!(message.contains("[RESOLVED] dns example.com"));
+        assert!(message.contains("hostname: web-1"));
+        assert!(messages.try_recv().is_err());
+
+        // Alerts after shutdown have nowhere to go, rather than being sent on their own
+        notifier.notify(&alert("late")).await;
+        assert!(messages.try_recv().is_err());
+    }
+}
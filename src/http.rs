@@ -1,15 +1,26 @@
 use async_trait::async_trait;
-use serde::Deserialize;
+use reqwest::tls::TlsInfo;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
 use simple_eyre::eyre::{eyre, Result, WrapErr};
+use time::OffsetDateTime;
+use x509_parser::prelude::{FromDer, X509Certificate};
 
-use crate::{CheckStatus, Checker as CheckerTrait, UpdateChan};
+use crate::{CheckStatus, Checker as CheckerTrait, Health, UpdateChan};
 
-#[derive(Clone, Default, Deserialize, Debug)]
+#[derive(Clone, Default, Deserialize, JsonSchema, Serialize, Debug)]
+#[schemars(rename = "HttpConfig")]
 pub struct Config {
+    /// The URL to request, which passes with any 2xx status
     url: String,
+    /// Accept invalid, expired or self-signed certificates
     #[serde(rename = "insecureIgnoreSSLCert")]
     #[serde(default)]
     insecure_ignore_ssl_cert: bool,
+    /// Warn when the server's certificate expires within this many days, and fail once it has expired
+    #[serde(rename = "certExpiryWarningDays")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cert_expiry_warning_days: Option<u32>,
     // TODO expected status codes
 }
 
@@ -21,8 +32,14 @@ pub struct Checker {
 
 impl Checker {
     pub fn new(id: usize, config: Config) -> Result<Self> {
+        let url = reqwest::Url::parse(&config.url).wrap_err(format!("Invalid URL '{}'", config.url))?;
+        if config.cert_expiry_warning_days.is_some() && url.scheme() != "https" {
+            return Err(eyre!("certExpiryWarningDays needs an https URL, not '{}'", config.url));
+        }
+
         let client = reqwest::ClientBuilder::new()
             .danger_accept_invalid_certs(config.insecure_ignore_ssl_cert)
+            .tls_info(config.cert_expiry_warning_days.is_some())
             .build()
             .wrap_err("Unable to construct http client")?;
 
@@ -30,6 +47,32 @@ impl Checker {
     }
 }
 
+/// When the certificate the server presented expires, if it presented one
+fn cert_not_after(response: &reqwest::Response) -> Result<Option<OffsetDateTime>> {
+    let Some(der) = response
+        .extensions()
+        .get::<TlsInfo>()
+        .and_then(|info| info.peer_certificate())
+    else {
+        return Ok(None);
+    };
+
+    let (_, cert) = X509Certificate::from_der(der).wrap_err("Invalid server certificate")?;
+    Ok(Some(cert.validity().not_after.to_datetime()))
+}
+
+/// Fails for a certificate which has expired, and warns about one expiring within `warning_days` whole days of `now`
+fn expiry_health(not_after: OffsetDateTime, now: OffsetDateTime, warning_days: u32) -> Result<Health> {
+    let days = (not_after - now).whole_days();
+    if not_after <= now {
+        Err(eyre!("Certificate has expired"))
+    } else if days < i64::from(warning_days) {
+        Ok(Health::Warning(format!("Certificate expires in {} days", days)))
+    } else {
+        Ok(Health::Healthy)
+    }
+}
+
 #[async_trait]
 impl CheckerTrait for Checker {
     fn id(&self) -> usize {
@@ -40,7 +83,18 @@ impl CheckerTrait for Checker {
         format!("http {}", self.config.url)
     }
 
-    async fn check(&self, updates: &UpdateChan) -> Result<()> {
+    fn instance(&self) -> String {
+        match reqwest::Url::parse(&self.config.url) {
+            Ok(url) => match (url.host_str(), url.port()) {
+                (Some(host), Some(port)) => format!("{}:{}", host, port),
+                (Some(host), None) => host.to_owned(),
+                _ => self.config.url.clone(),
+            },
+            Err(_) => self.config.url.clone(),
+        }
+    }
+
+    async fn check(&self, updates: &UpdateChan) -> Result<Health> {
         updates.send(CheckStatus::Running, "making request".to_owned());
 
         let response = self
@@ -62,6 +116,50 @@ impl CheckerTrait for Checker {
             return Err(eyre!(error));
         }
 
-        return Ok(());
+        match (self.config.cert_expiry_warning_days, cert_not_after(&response)?) {
+            (Some(warning_days), Some(not_after)) => expiry_health(not_after, OffsetDateTime::now_utc(), warning_days),
+            _ => Ok(Health::Healthy),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use time::macros::datetime;
+
+    use super::*;
+
+    fn expiry(not_after: OffsetDateTime) -> String {
+        match expiry_health(not_after, datetime!(2024-03-01 12:00 UTC), 14) {
+            Ok(Health::Healthy) => "healthy".to_owned(),
+            Ok(Health::Warning(msg)) => format!("warning: {}", msg),
+            Err(err) => format!("error: {}", err),
+        }
+    }
+
+    #[test]
+    fn test_expiry_health() {
+        assert_eq!(expiry(datetime!(2024-06-01 00:00 UTC)), "healthy");
+        assert_eq!(expiry(datetime!(2024-03-15 12:00 UTC)), "healthy");
+        assert_eq!(
+            expiry(datetime!(2024-03-15 11:00 UTC)),
+            "warning: Certificate expires in 13 days"
+        );
+        assert_eq!(
+            expiry(datetime!(2024-03-01 13:00 UTC)),
+            "warning: Certificate expires in 0 days"
+        );
+    }
+
+    #[test]
+    fn test_expired_certificate_fails() {
+        assert_eq!(
+            expiry(datetime!(2024-03-01 12:00 UTC)),
+            "error: Certificate has expired"
+        );
+        assert_eq!(
+            expiry(datetime!(2023-12-01 00:00 UTC)),
+            "error: Certificate has expired"
+        );
     }
 }
@@ -0,0 +1,137 @@
+use std::env;
+use std::fs;
+
+use serde_json::Value;
+use simple_eyre::eyre::{eyre, Result, WrapErr};
+
+/// The kinds of substitution, as they're written after `${`
+const KINDS: [&str; 2] = ["env:", "file:"];
+
+/// Resolves a single `${kind:arg}` substitution
+fn resolve(kind: &str, arg: &str) -> Result<String> {
+    match kind {
+        "env" => env::var(arg).map_err(|_| eyre!("environment variable '{}' is not set", arg)),
+        _ => {
+            let contents = fs::read_to_string(arg).wrap_err(format!("Unable to read '{}'", arg))?;
+            // Secret files usually end in a newline which isn't part of the secret
+            Ok(contents
+                .strip_suffix('\n')
+                .unwrap_or(&contents)
+                .trim_end_matches('\r')
+                .to_owned())
+        }
+    }
+}
+
+/// Substitutes `${env:NAME}` and `${file:PATH}` in a string, with `$${env:` and `$${file:` giving them literally
+///
+/// Any other `${`, such as a shell variable in a command, is left as it is.
+fn interpolate_str(s: &str) -> Result<String> {
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+
+    while let Some(start) = rest.find("${") {
+        let after = &rest[start + 2..];
+        if !KINDS.iter().any(|kind| after.starts_with(kind)) {
+            out.push_str(&rest[..start + 2]);
+            rest = after;
+            continue;
+        }
+
+        if rest[..start].ends_with('$') {
+            out.push_str(&rest[..start - 1]);
+            out.push_str("${");
+            rest = after;
+            continue;
+        }
+
+        out.push_str(&rest[..start]);
+
+        let Some(len) = after.find('}') else {
+            return Err(eyre!("unclosed '${{' in '{}'", s));
+        };
+        let (kind, arg) = after[..len].split_once(':').expect("substitutions have a kind");
+        out.push_str(&resolve(kind, arg)?);
+
+        rest = &after[len + 1..];
+    }
+
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// Substitutes environment variables and file contents into every string in a config, in place
+///
+/// Errors name the JSON path of the string they were found in, starting from `path`.
+pub fn interpolate(value: &mut Value, path: &str) -> Result<()> {
+    match value {
+        Value::String(s) if s.contains("${") => {
+            *s = interpolate_str(s).wrap_err(path.to_owned())?;
+        }
+        Value::Array(items) => {
+            for (index, item) in items.iter_mut().enumerate() {
+                interpolate(item, &format!("{}[{}]", path, index))?;
+            }
+        }
+        Value::Object(fields) => {
+            for (key, field) in fields.iter_mut() {
+                let field_path = if path.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}.{}", path, key)
+                };
+                interpolate(field, &field_path)?;
+            }
+        }
+        _ => {}
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_interpolate() {
+        let secret = env::temp_dir().join(format!("colmena-health-test-{}", std::process::id()));
+        fs::write(&secret, "hunter2\n").unwrap();
+        env::set_var("COLMENA_HEALTH_TEST_HOST", "web-1");
+
+        let mut config = json!({
+            "checks": [{
+                "params": {
+                    "url": "https://${env:COLMENA_HEALTH_TEST_HOST}/health",
+                    "headers": { "Authorization": format!("Bearer ${{file:{}}}", secret.display()) },
+                },
+            }],
+            "literal": "$${env:NOT_SUBSTITUTED}",
+            "command": "test -d ${HOME} && echo $${PATH}",
+        });
+        interpolate(&mut config, "").unwrap();
+        fs::remove_file(&secret).unwrap();
+
+        assert_eq!(
+            config,
+            json!({
+                "checks": [{
+                    "params": {
+                        "url": "https://web-1/health",
+                        "headers": { "Authorization": "Bearer hunter2" },
+                    },
+                }],
+                "literal": "${env:NOT_SUBSTITUTED}",
+                "command": "test -d ${HOME} && echo $${PATH}",
+            })
+        );
+
+        let mut missing = json!({ "checks": [{ "params": { "url": "${env:COLMENA_HEALTH_TEST_UNSET}" } }] });
+        assert_eq!(
+            format!("{:#}", interpolate(&mut missing, "").unwrap_err()),
+            "checks[0].params.url: environment variable 'COLMENA_HEALTH_TEST_UNSET' is not set"
+        );
+    }
+}
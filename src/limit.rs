@@ -0,0 +1,164 @@
+use std::collections::HashMap;
+use std::num::NonZeroUsize;
+use std::sync::Arc;
+
+use schemars::JsonSchema;
+use serde::Deserialize;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Bounds how many checks run at once
+#[derive(Clone, Default, Deserialize, JsonSchema, Debug)]
+#[schemars(rename = "ConcurrencyConfig")]
+#[serde(rename_all = "camelCase")]
+pub struct Config {
+    /// The most checks which may run at once, or unlimited if not given
+    #[serde(default)]
+    pub max_concurrency: Option<NonZeroUsize>,
+    /// Limits on how many checks sharing a label value may run at once
+    #[serde(default)]
+    pub label_limits: Vec<LabelLimit>,
+}
+
+#[derive(Clone, Deserialize, JsonSchema, Debug)]
+pub struct LabelLimit {
+    /// The label to group checks by, which may be the automatic `check_type` or `instance` label
+    pub label: String,
+    /// The most checks with the same value for the label which may run at once
+    pub max: NonZeroUsize,
+}
+
+/// The semaphores a check needs a permit from to run, in the order they're acquired
+#[derive(Clone, Default)]
+pub struct Limits(Vec<Arc<Semaphore>>);
+
+impl Limits {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Waits for a permit from every semaphore in turn
+    ///
+    /// Every check acquires label limits in config order and then the global limit, so no two checks can each hold a
+    /// permit the other is waiting for. Taking the global limit last means a check queued behind a busy host doesn't
+    /// hold a global permit that checks on other hosts could be using. Tokio's semaphores queue waiters first come,
+    /// first served.
+    pub async fn acquire(&self) -> Vec<OwnedSemaphorePermit> {
+        let mut permits = Vec::with_capacity(self.0.len());
+        for semaphore in self.0.iter() {
+            let permit = semaphore.clone().acquire_owned().await;
+            permits.push(permit.expect("limit semaphores are never closed"));
+        }
+
+        permits
+    }
+}
+
+/// Hands out the semaphores for concurrency limits, keeping them across reloads so new and running checks share them
+#[derive(Default)]
+pub struct Limiter {
+    config: Config,
+    /// Keyed by the limit's position, label, the label's value and the limit itself, with the global limit at 0
+    semaphores: HashMap<(usize, String, String, usize), Arc<Semaphore>>,
+}
+
+impl Limiter {
+    pub fn configure(&mut self, config: Config) {
+        self.config = config;
+
+        // Semaphores only held here belong to limits or checks which have since gone away
+        self.semaphores.retain(|_, semaphore| Arc::strong_count(semaphore) > 1);
+    }
+
+    fn semaphore(&mut self, key: (usize, String, String, usize)) -> Arc<Semaphore> {
+        let permits = key.3;
+        self.semaphores
+            .entry(key)
+            .or_insert_with(|| Arc::new(Semaphore::new(permits)))
+            .clone()
+    }
+
+    /// The limits applying to a check with the given labels
+    pub fn for_labels(&mut self, labels: &HashMap<String, String>) -> Limits {
+        let mut semaphores = Vec::new();
+
+        for (index, limit) in self.config.label_limits.clone().into_iter().enumerate() {
+            if let Some(value) = labels.get(&limit.label) {
+                semaphores.push(self.semaphore((index + 1, limit.label, value.clone(), limit.max.get())));
+            }
+        }
+
+        if let Some(max) = self.config.max_concurrency {
+            semaphores.push(self.semaphore((0, String::new(), String::new(), max.get())));
+        }
+
+        Limits(semaphores)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn labels(hostname: &str, check_type: &str) -> HashMap<String, String> {
+        HashMap::from([
+            ("hostname".to_owned(), hostname.to_owned()),
+            ("check_type".to_owned(), check_type.to_owned()),
+        ])
+    }
+
+    #[test]
+    fn test_limits() {
+        let config: Config = serde_json::from_value(serde_json::json!({
+            "maxConcurrency": 10,
+            "labelLimits": [{ "label": "hostname", "max": 1 }, { "label": "check_type", "max": 2 }],
+        }))
+        .unwrap();
+
+        let mut limiter = Limiter::default();
+        limiter.configure(config);
+
+        let web_ssh = limiter.for_labels(&labels("web-1", "ssh"));
+        let web_http = limiter.for_labels(&labels("web-1", "http"));
+        let db_ssh = limiter.for_labels(&labels("db-1", "ssh"));
+
+        // Checks share the global semaphore, last, and those of the label values they have in common
+        assert_eq!(web_ssh.0.len(), 3);
+        assert!(Arc::ptr_eq(&web_ssh.0[0], &web_http.0[0]));
+        assert!(!Arc::ptr_eq(&web_ssh.0[0], &db_ssh.0[0]));
+        assert!(Arc::ptr_eq(&web_ssh.0[1], &db_ssh.0[1]));
+        assert!(Arc::ptr_eq(&web_ssh.0[2], &db_ssh.0[2]));
+        assert_eq!(web_ssh.0[0].available_permits(), 1);
+        assert_eq!(web_ssh.0[2].available_permits(), 10);
+
+        let unlabelled = limiter.for_labels(&HashMap::new());
+        assert_eq!(unlabelled.0.len(), 1);
+
+        assert!(serde_json::from_value::<Config>(serde_json::json!({ "maxConcurrency": 0 })).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_queued_checks_leave_global_permits() {
+        let config: Config = serde_json::from_value(serde_json::json!({
+            "maxConcurrency": 2,
+            "labelLimits": [{ "label": "hostname", "max": 1 }],
+        }))
+        .unwrap();
+
+        let mut limiter = Limiter::default();
+        limiter.configure(config);
+
+        let web_1 = limiter.for_labels(&labels("web-1", "ssh"));
+        let web_2 = limiter.for_labels(&labels("web-1", "http"));
+        let db = limiter.for_labels(&labels("db-1", "ssh"));
+
+        let _running = web_1.acquire().await;
+
+        // Queued behind the busy host, without taking the global permit left over
+        let queued = tokio::spawn(async move { web_2.acquire().await });
+        tokio::task::yield_now().await;
+        assert!(!queued.is_finished());
+
+        let permits = tokio::time::timeout(std::time::Duration::from_secs(1), db.acquire()).await;
+        assert_eq!(permits.expect("another host's check should run").len(), 2);
+    }
+}
@@ -0,0 +1,115 @@
+use serde_json::json;
+use simple_eyre::eyre::Result;
+
+use crate::reload::LoadedCheck;
+
+const HEADERS: [&str; 7] = [
+    "ID",
+    "NAME",
+    "TYPE",
+    "TIMEOUT",
+    "RETRY POLICY",
+    "ALERT POLICY",
+    "LABELS",
+];
+
+fn row(check: &LoadedCheck) -> [String; 7] {
+    let mut labels: Vec<String> = check
+        .info
+        .labels
+        .iter()
+        .map(|(name, value)| format!("{}={}", name, value))
+        .collect();
+    labels.sort();
+
+    let policy = &check.info.alert_policy;
+
+    [
+        check.runnable.checker.id().to_string(),
+        check.info.name.clone(),
+        check.info.check_type.clone(),
+        format!("{:?}", check.runnable.timeout),
+        check.runnable.retry_policy.to_string(),
+        match policy.schedule {
+            Some(ref schedule) => format!(
+                "at '{}' {}, {:?} when failing",
+                schedule,
+                policy.timezone.unwrap_or_default(),
+                policy.recheck_interval
+            ),
+            None => format!(
+                "every {:?}, {:?} when failing",
+                policy.check_interval, policy.recheck_interval
+            ),
+        },
+        labels.join(","),
+    ]
+}
+
+/// Lays out rows as a table, with columns separated by at least two spaces
+fn table(rows: &[[String; 7]]) -> String {
+    let mut widths = HEADERS.map(str::len);
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row.iter()) {
+            *width = (*width).max(cell.len());
+        }
+    }
+
+    let headers = HEADERS.map(str::to_owned);
+    std::iter::once(&headers)
+        .chain(rows.iter())
+        .map(|row| {
+            let cells: Vec<String> = row
+                .iter()
+                .zip(widths.iter())
+                .map(|(cell, width)| format!("{:width$}", cell, width = width))
+                .collect();
+            cells.join("  ").trim_end().to_owned()
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+pub fn run_list(checks: &[LoadedCheck], as_json: bool) -> Result<()> {
+    if as_json {
+        let checks: Vec<_> = checks
+            .iter()
+            .map(|check| {
+                json!({
+                    "id": check.runnable.checker.id(),
+                    "name": check.info.name,
+                    "type": check.info.check_type,
+                    "labels": check.info.labels,
+                    "checkTimeout": check.runnable.timeout.as_secs_f64(),
+                    "retryPolicy": check.runnable.retry_policy,
+                    "alertPolicy": check.info.alert_policy,
+                })
+            })
+            .collect();
+
+        println!("{}", serde_json::to_string_pretty(&checks)?);
+        return Ok(());
+    }
+
+    let rows: Vec<_> = checks.iter().map(row).collect();
+    println!("{}", table(&rows));
+    println!("{} check(s) selected", checks.len());
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_table() {
+        let rows = [["0", "http http://web-1/", "http", "10s", "", "", "hostname=web-1"].map(str::to_owned)];
+
+        assert_eq!(
+            table(&rows),
+            "ID  NAME                TYPE  TIMEOUT  RETRY POLICY  ALERT POLICY  LABELS\n\
+             0   http http://web-1/  http  10s                                  hostname=web-1"
+        );
+    }
+}
@@ -1,46 +1,74 @@
 use std::collections::HashMap;
 use std::fmt::{self, Debug, Display};
-use std::fs;
-use std::io::{stdin, Read};
 use std::rc::Rc;
-use std::time::Duration;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use alert::run_alerts;
 use async_trait::async_trait;
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use log::{debug, error};
-use simple_eyre::eyre::{Result, WrapErr};
+use simple_eyre::eyre::{eyre, Result, WrapErr};
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
 use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
-use tokio::time::timeout as tokio_timeout;
+use tokio::time::{sleep, timeout as tokio_timeout};
 
 use report::run_report;
 
 mod alert;
 mod alertmanager;
 mod config;
+mod control;
 mod dns;
+mod email;
 mod http;
+mod interpolate;
+mod limit;
+mod list;
+mod maintenance;
+mod nixos;
+mod notify;
+mod reload;
 mod report;
 mod retry;
+mod sanitize;
 mod select;
+mod source;
 mod ssh;
+mod template;
+mod validate;
+mod webhook;
 
 #[async_trait]
 pub trait Checker {
     fn id(&self) -> usize;
     fn name(&self) -> String;
-    async fn check(&self, updates: &UpdateChan) -> Result<()>;
+    /// The host or service being checked, used as the `instance` label on alerts
+    fn instance(&self) -> String;
+    /// Runs the check once; errors are critical failures, while degraded checks return a warning
+    async fn check(&self, updates: &UpdateChan) -> Result<Health>;
+}
+
+/// The state of a check that didn't fail outright
+pub enum Health {
+    Healthy,
+    Warning(String),
 }
 
 enum CheckStatus {
+    // Waiting for a permit from its concurrency limits
+    Queued,
     // Currently Running
     Running,
-    // Waiting for Retry
-    Retrying,
-    // Waiting to Run
-    Waiting(Duration, String),
+    // Waiting for Retry, as the given attempt after the given delay
+    Retrying(u16, Duration),
+    // Waiting to Run, until the given time
+    Waiting(Duration, OffsetDateTime, String),
     // Check succeeded
     Succeeded,
+    // Check succeeded, but is degraded
+    Warning,
     // Check failed
     Failed,
 }
@@ -48,10 +76,18 @@ enum CheckStatus {
 impl Display for CheckStatus {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
+            CheckStatus::Queued => write!(f, "Queued for a concurrency limit"),
             CheckStatus::Running => write!(f, "Running"),
-            CheckStatus::Retrying => write!(f, "Waiting to retry"),
-            CheckStatus::Waiting(secs, waiting_for) => write!(f, "Waiting {:?} for {}", secs, waiting_for),
+            CheckStatus::Retrying(attempt, delay) => write!(f, "Waiting {:?} to retry (retry {})", delay, attempt),
+            CheckStatus::Waiting(secs, until, waiting_for) => write!(
+                f,
+                "Waiting {:?} for {}, until {}",
+                secs,
+                waiting_for,
+                until.format(&Rfc3339).unwrap_or_default()
+            ),
             CheckStatus::Succeeded => write!(f, "Succeeded"),
+            CheckStatus::Warning => write!(f, "Succeeded with warning:"),
             CheckStatus::Failed => write!(f, "Failed:"),
         }
     }
@@ -60,6 +96,13 @@ impl Display for CheckStatus {
 #[derive(Clone)]
 pub struct CheckInfo {
     name: String,
+    check_type: String,
+    instance: String,
+    params: serde_json::Value,
+    alert_policy: alert::Policy,
+    /// How long a single attempt may take, which the check may spend without sending an update
+    check_timeout: Duration,
+    maintenance: maintenance::Windows,
     labels: HashMap<String, String>,
     annotations: HashMap<String, String>,
 }
@@ -68,29 +111,44 @@ pub struct CheckUpdate {
     id: usize,
     status: CheckStatus,
     msg: Option<String>,
+    /// How long the check attempt this update is about took
+    duration: Option<Duration>,
 }
 
 #[derive(Clone)]
 pub struct UpdateChan {
     id: usize,
+    sanitizer: Arc<sanitize::Sanitizer>,
     updates: UnboundedSender<CheckUpdate>,
 }
 
 impl UpdateChan {
-    fn new(id: usize, updates: UnboundedSender<CheckUpdate>) -> Self {
-        UpdateChan { id, updates }
+    fn new(id: usize, sanitizer: Arc<sanitize::Sanitizer>, updates: UnboundedSender<CheckUpdate>) -> Self {
+        UpdateChan { id, sanitizer, updates }
     }
 
     fn send<M>(&self, status: CheckStatus, msg: M)
     where
         M: Into<Option<String>>,
     {
+        self.send_update(status, msg.into(), None)
+    }
+
+    fn send_timed<M>(&self, status: CheckStatus, msg: M, duration: Duration)
+    where
+        M: Into<Option<String>>,
+    {
+        self.send_update(status, msg.into(), Some(duration))
+    }
+
+    fn send_update(&self, status: CheckStatus, msg: Option<String>, duration: Option<Duration>) {
         if self
             .updates
             .send(CheckUpdate {
                 id: self.id,
                 status,
-                msg: msg.into(),
+                msg: msg.map(|msg| self.sanitizer.sanitize(&msg)),
+                duration,
             })
             .is_err()
         {
@@ -101,6 +159,7 @@ impl UpdateChan {
 
 enum CheckResult {
     Success,
+    Warning,
     Failure,
 }
 
@@ -112,62 +171,166 @@ impl CheckResult {
 
         false
     }
+
+    fn is_warning(&self) -> bool {
+        if let CheckResult::Warning = self {
+            return true;
+        }
+
+        false
+    }
 }
 
 #[derive(Clone)]
 pub struct RunnableCheck {
     alert_policy: alert::Policy,
+    /// Derived from the check's identity, to spread its runs out the same way every time
+    spread_key: u64,
+    maintenance: maintenance::Windows,
     checker: Rc<dyn Checker>,
     retry_policy: retry::Policy,
     timeout: Duration,
+    warn_after: Option<Duration>,
+    fail_after: Option<Duration>,
+    limits: limit::Limits,
     updates: UpdateChan,
 }
 
+impl RunnableCheck {
+    /// Downgrades the result of a check attempt which took longer than its latency thresholds allow
+    fn check_latency(&self, health: Health, elapsed: Duration) -> Result<Health> {
+        if let Some(fail_after) = self.fail_after {
+            if elapsed > fail_after {
+                return Err(eyre!(
+                    "Check took {:?}, longer than failAfter ({:?})",
+                    elapsed,
+                    fail_after
+                ));
+            }
+        }
+
+        if let (Health::Healthy, Some(warn_after)) = (&health, self.warn_after) {
+            if elapsed > warn_after {
+                return Ok(Health::Warning(format!(
+                    "Check took {:?}, longer than warnAfter ({:?})",
+                    elapsed, warn_after
+                )));
+            }
+        }
+
+        Ok(health)
+    }
+}
+
 async fn run_check(check: RunnableCheck) -> CheckResult {
     let mut retrier = retry::Retrier::new(check.retry_policy.clone());
     debug!("Running check - {}", check.checker.name());
 
-    let mut last_output: Option<String> = None;
     loop {
+        if !check.limits.is_empty() {
+            check.updates.send(CheckStatus::Queued, None);
+        }
+        let permits = check.limits.acquire().await;
         check.updates.send(CheckStatus::Running, None);
 
-        match tokio_timeout(check.timeout, check.checker.check(&check.updates))
+        let started = Instant::now();
+        let result = tokio_timeout(check.timeout, check.checker.check(&check.updates))
             .await
-            .wrap_err("Check timed out")
-        {
-            Ok(Ok(_)) => {
-                check.updates.send(CheckStatus::Succeeded, None);
+            .wrap_err("Check timed out");
+        let elapsed = started.elapsed();
+        drop(permits);
+
+        let output = match result.and_then(|result| result.and_then(|health| check.check_latency(health, elapsed))) {
+            Ok(Health::Healthy) => {
+                check.updates.send_timed(CheckStatus::Succeeded, None, elapsed);
                 return CheckResult::Success;
             }
-            Err(err) | Ok(Err(err)) => {
-                let msg = format!("{:#}", err);
-                check.updates.send(CheckStatus::Retrying, msg.clone());
-                last_output = Some(msg);
+            Ok(Health::Warning(msg)) => {
+                check.updates.send_timed(CheckStatus::Warning, msg, elapsed);
+                return CheckResult::Warning;
             }
-        }
+            Err(err) => format!("{:#}", err),
+        };
 
-        if retrier.retry().await.is_none() {
-            check.updates.send(
-                CheckStatus::Failed,
-                format!(
-                    "Maximum retries reached: {}",
-                    last_output.unwrap_or("- no output -".to_owned())
-                ),
-            );
-            return CheckResult::Failure;
+        match retrier.next_retry() {
+            Ok((attempt, delay)) => {
+                check
+                    .updates
+                    .send_timed(CheckStatus::Retrying(attempt, delay), output, elapsed);
+                sleep(delay).await;
+            }
+            Err(give_up) => {
+                check
+                    .updates
+                    .send_timed(CheckStatus::Failed, format!("{}: {}", give_up, output), elapsed);
+                return CheckResult::Failure;
+            }
         }
     }
 }
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Check a configuration file for mistakes without running any checks
+    Validate {
+        /// A label-based query selector to check matches at least one check
+        #[clap(short, long)]
+        select: Option<String>,
+        /// The format of the configuration files, guessed from their extensions if not given
+        #[clap(long, value_enum)]
+        format: Option<config::Format>,
+        /// The configuration files or directories to validate, merged in order
+        #[clap(required = true)]
+        config_files: Vec<String>,
+    },
+    /// Show which checks would run, without running them
+    List {
+        /// A label-based query selector, e.g. hostname:web-1,web-2
+        #[clap(short, long)]
+        select: Option<String>,
+        /// Print the checks as JSON rather than a table
+        #[clap(long)]
+        json: bool,
+        /// The format of the configuration files, guessed from their extensions if not given
+        #[clap(long, value_enum)]
+        format: Option<config::Format>,
+        /// The configuration files or directories containing check definitions, merged in order
+        #[clap(required = true)]
+        config_files: Vec<String>,
+    },
+    /// Print a JSON Schema describing the configuration file format
+    Schema,
+    /// Print the NixOS module defining `deployment.healthchecks`, as found in options.nix
+    NixosOptions,
+}
+
 #[derive(Parser, Debug)]
+#[clap(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
+    #[clap(subcommand)]
+    command: Option<Command>,
     /// A label-based query selector, e.g. hostname:web-1,web-2
     #[clap(short, long)]
     select: Option<String>,
-    /// The configuration file containing check definitions
-    config_file: String,
+    /// The configuration files or directories containing check definitions, merged in order and reloaded on SIGHUP in
+    /// alerting mode
+    #[clap(required = true)]
+    config_files: Vec<String>,
+    /// The format of the configuration files, guessed from their extensions if not given
+    #[clap(long, value_enum)]
+    format: Option<config::Format>,
     /// Enable alerting mode
     #[clap(long)]
     alert: bool,
+    /// Exit with an error if any check warned, rather than only on failures
+    #[clap(long)]
+    fail_on_warning: bool,
+}
+
+fn parse_selector(sel: Option<String>) -> Result<Option<select::Term>> {
+    Ok(match sel {
+        Some(sel) => Some(sel.parse()?),
+        None => None,
+    })
 }
 
 fn main() -> Result<()> {
@@ -177,61 +340,58 @@ fn main() -> Result<()> {
 
     let args = Args::parse();
 
-    let label_selector: Option<select::Term> = match args.select {
-        Some(sel) => Some(sel.parse()?),
-        None => None,
-    };
-
-    let config_data = if args.config_file == "-" {
-        let mut buf = String::new();
-        stdin().read_to_string(&mut buf)?;
-        buf
-    } else {
-        fs::read_to_string(args.config_file)?
-    };
-    let config: config::Config = serde_json::from_str(&config_data)?;
-
-    let mut checks = Vec::new();
-    let (tx, rx) = unbounded_channel::<CheckUpdate>();
-
-    let mut check_registry = HashMap::new();
-
-    for (id, check_def) in config.checks.into_iter().enumerate() {
-        if let Some(ref sel) = label_selector {
-            if !sel.matches(&check_def.labels) {
-                continue;
-            }
+    match args.command {
+        Some(Command::Validate {
+            select,
+            format,
+            config_files,
+        }) => {
+            return validate::run_validate(config_files, format, parse_selector(select)?);
         }
-
-        let checker = check_def.config.clone().into_check(id)?;
-        check_registry.insert(
-            id,
-            CheckInfo {
-                name: checker.name(),
-                labels: check_def.labels.clone(),
-                annotations: check_def.annotations.clone(),
-            },
-        );
-
-        let runnable = RunnableCheck {
-            alert_policy: check_def.alert_policy,
-            checker,
-            retry_policy: check_def.retry_policy,
-            timeout: check_def.check_timeout,
-            updates: UpdateChan::new(id, tx.clone()),
-        };
-
-        checks.push(runnable);
+        Some(Command::List {
+            select,
+            json,
+            format,
+            config_files,
+        }) => {
+            let (tx, _rx) = unbounded_channel::<CheckUpdate>();
+            let source = source::Source::new(config_files, format);
+            let loaded = reload::CheckSet::new(source, parse_selector(select)?, tx).load()?;
+            return list::run_list(&loaded.checks, json);
+        }
+        Some(Command::Schema) => {
+            println!("{}", serde_json::to_string_pretty(&config::schema())?);
+            return Ok(());
+        }
+        Some(Command::NixosOptions) => {
+            print!("{}", nixos::options());
+            return Ok(());
+        }
+        None => {}
     }
 
-    drop(tx);
+    let label_selector = parse_selector(args.select)?;
+    let source = source::Source::new(args.config_files, args.format);
+
+    let (tx, rx) = unbounded_channel::<CheckUpdate>();
+    let mut check_set = reload::CheckSet::new(source, label_selector, tx);
+    let loaded = check_set.load()?;
 
     if args.alert {
-        if let Some(alert_cfg) = config.alerting {
-            run_alerts(checks, check_registry, rx, alert_cfg)?;
+        if let Some(alert_cfg) = loaded.alerting.clone() {
+            run_alerts(check_set, loaded, rx, alert_cfg)?;
         }
     } else {
-        run_report(checks, check_registry, rx)?;
+        // Reports finish once every check has, so nothing else may hold on to the update channel
+        drop(check_set);
+
+        let check_registry = loaded
+            .checks
+            .iter()
+            .map(|check| (check.runnable.checker.id(), check.info.clone()))
+            .collect();
+        let checks = loaded.checks.into_iter().map(|check| check.runnable).collect();
+        run_report(checks, check_registry, rx, args.fail_on_warning)?;
     }
 
     Ok(())
@@ -242,3 +402,69 @@ fn verify_cli() {
     use clap::CommandFactory;
     Args::command().debug_assert();
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn runnable(warn_after: Option<u64>, fail_after: Option<u64>) -> RunnableCheck {
+        let check_config: config::CheckConfig =
+            serde_json::from_value(json!({ "type": "dns", "params": { "domain": "example.com." } })).unwrap();
+        let (tx, _) = unbounded_channel();
+
+        RunnableCheck {
+            alert_policy: alert::Policy::default(),
+            spread_key: 0,
+            maintenance: maintenance::Windows::default(),
+            checker: check_config.into_check(0).unwrap(),
+            retry_policy: retry::Policy::default(),
+            timeout: Duration::from_secs(10),
+            warn_after: warn_after.map(Duration::from_secs),
+            fail_after: fail_after.map(Duration::from_secs),
+            limits: limit::Limits::default(),
+            updates: UpdateChan::new(
+                0,
+                Arc::new(sanitize::Sanitizer::new(sanitize::Config::default()).unwrap()),
+                tx,
+            ),
+        }
+    }
+
+    /// The outcome of a check attempt after its latency is taken into account, as a string
+    fn latency(check: &RunnableCheck, health: Health, secs: u64) -> String {
+        match check.check_latency(health, Duration::from_secs(secs)) {
+            Ok(Health::Healthy) => "healthy".to_owned(),
+            Ok(Health::Warning(msg)) => format!("warning: {}", msg),
+            Err(err) => format!("error: {}", err),
+        }
+    }
+
+    #[test]
+    fn test_check_latency() {
+        let check = runnable(Some(2), Some(5));
+
+        assert_eq!(latency(&check, Health::Healthy, 1), "healthy");
+        assert_eq!(latency(&check, Health::Healthy, 2), "healthy");
+        assert_eq!(
+            latency(&check, Health::Healthy, 3),
+            "warning: Check took 3s, longer than warnAfter (2s)"
+        );
+        assert_eq!(
+            latency(&check, Health::Healthy, 6),
+            "error: Check took 6s, longer than failAfter (5s)"
+        );
+
+        // A warning from the check itself is more useful than one about its latency, unless it's too slow to pass
+        let degraded = || Health::Warning("certificate expires in 3 days".to_owned());
+        assert_eq!(latency(&check, degraded(), 3), "warning: certificate expires in 3 days");
+        assert_eq!(
+            latency(&check, degraded(), 6),
+            "error: Check took 6s, longer than failAfter (5s)"
+        );
+
+        // Without thresholds, only the timeout limits how long a check may take
+        assert_eq!(latency(&runnable(None, None), Health::Healthy, 9), "healthy");
+    }
+}
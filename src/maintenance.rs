@@ -0,0 +1,442 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use schemars::gen::SchemaGenerator;
+use schemars::schema::Schema;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde_json::Value;
+use simple_eyre::eyre::{eyre, Result, WrapErr};
+use time::{OffsetDateTime, Time, Weekday};
+
+use crate::select;
+
+/// What happens to matching checks during a maintenance window
+#[derive(Clone, Copy, Default, Deserialize, JsonSchema, Debug, PartialEq)]
+#[schemars(rename = "MaintenanceMode")]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    /// Checks keep running, but don't alert
+    #[default]
+    Silence,
+    /// Checks don't run at all
+    Skip,
+}
+
+#[derive(Clone, Copy, Deserialize, JsonSchema, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Day {
+    Mon,
+    Tue,
+    Wed,
+    Thu,
+    Fri,
+    Sat,
+    Sun,
+}
+
+impl Day {
+    fn weekday(&self) -> Weekday {
+        match self {
+            Day::Mon => Weekday::Monday,
+            Day::Tue => Weekday::Tuesday,
+            Day::Wed => Weekday::Wednesday,
+            Day::Thu => Weekday::Thursday,
+            Day::Fri => Weekday::Friday,
+            Day::Sat => Weekday::Saturday,
+            Day::Sun => Weekday::Sunday,
+        }
+    }
+}
+
+/// A time of day, given as `HH:MM` in UTC
+#[derive(Clone, Copy, Deserialize, Debug)]
+#[serde(try_from = "String")]
+pub struct TimeOfDay(Time);
+
+impl TryFrom<String> for TimeOfDay {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let invalid = || format!("Invalid time of day '{}', expected HH:MM", value);
+
+        let (hour, minute) = value.split_once(':').ok_or_else(invalid)?;
+        let hour = hour.parse().map_err(|_| invalid())?;
+        let minute = minute.parse().map_err(|_| invalid())?;
+
+        Ok(TimeOfDay(Time::from_hms(hour, minute, 0).map_err(|_| invalid())?))
+    }
+}
+
+#[derive(Clone, Deserialize, JsonSchema, Debug)]
+#[serde(rename_all = "camelCase")]
+pub enum Schedule {
+    /// A single window between two RFC3339 timestamps
+    Once {
+        #[serde(with = "time::serde::rfc3339")]
+        #[schemars(with = "String")]
+        start: OffsetDateTime,
+        #[serde(with = "time::serde::rfc3339")]
+        #[schemars(with = "String")]
+        end: OffsetDateTime,
+    },
+    /// A window recurring on the given days, in UTC; windows ending before they start run past midnight
+    Weekly {
+        days: Vec<Day>,
+        #[schemars(with = "String")]
+        start: TimeOfDay,
+        #[schemars(with = "String")]
+        end: TimeOfDay,
+    },
+}
+
+impl Schedule {
+    /// When the occurrence of the window containing `now` ends, if `now` is within one
+    fn end(&self, now: OffsetDateTime) -> Option<OffsetDateTime> {
+        match self {
+            Schedule::Once { start, end } => (*start <= now && now < *end).then_some(*end),
+            Schedule::Weekly { days, start, end } => {
+                let now = now.to_offset(time::UtcOffset::UTC);
+                let on = |weekday: Weekday| days.iter().any(|day| day.weekday() == weekday);
+                let time = now.time();
+
+                if start.0 <= end.0 {
+                    (on(now.weekday()) && start.0 <= time && time < end.0).then(|| now.replace_time(end.0))
+                } else if on(now.weekday()) && start.0 <= time {
+                    Some(now.replace_time(end.0) + time::Duration::DAY)
+                } else if on(now.weekday().previous()) && time < end.0 {
+                    Some(now.replace_time(end.0))
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    fn contains(&self, now: OffsetDateTime) -> bool {
+        self.end(now).is_some()
+    }
+}
+
+#[derive(Clone, Deserialize, JsonSchema, Debug)]
+#[schemars(rename = "MaintenanceWindow")]
+#[serde(rename_all = "camelCase")]
+pub struct Config {
+    /// A label selector, as accepted by `--select`, for the checks this window applies to; all checks if omitted
+    pub select: Option<String>,
+    #[serde(default)]
+    mode: Mode,
+    #[serde(flatten)]
+    #[schemars(schema_with = "schedule_schema")]
+    schedule: Schedule,
+}
+
+impl Config {
+    /// Rejects one-off windows which would never be active, as they end before they start
+    pub fn check_schedule(&self) -> Result<()> {
+        match self.schedule {
+            Schedule::Once { start, end } if start >= end => Err(eyre!(
+                "Maintenance window start ({}) is not before its end ({})",
+                start,
+                end
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The schema of a flattened `Schedule`, without schemars forbidding the keys of the surrounding `Config`
+fn schedule_schema(gen: &mut SchemaGenerator) -> Schema {
+    let mut schema = Schedule::json_schema(gen).into_object();
+    for variant in schema.subschemas().one_of.iter_mut().flatten() {
+        if let Schema::Object(variant) = variant {
+            variant.object().additional_properties = None;
+        }
+    }
+
+    Schema::Object(schema)
+}
+
+pub struct Window {
+    selector: Option<select::Term>,
+    mode: Mode,
+    schedule: Schedule,
+}
+
+impl Window {
+    fn new(config: Config) -> Result<Self> {
+        config.check_schedule()?;
+
+        let selector = match config.select {
+            Some(ref sel) => Some(
+                sel.parse()
+                    .wrap_err(format!("Invalid maintenance window selector '{}'", sel))?,
+            ),
+            None => None,
+        };
+
+        Ok(Window {
+            selector,
+            mode: config.mode,
+            schedule: config.schedule,
+        })
+    }
+
+    fn selects(&self, labels: &HashMap<String, String>) -> bool {
+        match self.selector {
+            Some(ref sel) => sel.matches(labels),
+            None => true,
+        }
+    }
+
+    fn applies(&self, labels: &HashMap<String, String>, now: OffsetDateTime) -> bool {
+        self.selects(labels) && self.schedule.contains(now)
+    }
+}
+
+/// A window added at runtime, kept along with the definition it was added with
+struct RuntimeWindow {
+    id: u64,
+    definition: Value,
+    window: Window,
+}
+
+#[derive(Default)]
+struct Store {
+    configured: Vec<Window>,
+    runtime: Vec<RuntimeWindow>,
+    next_id: u64,
+}
+
+/// Every maintenance window, from the config or added at runtime, shared with every check so changes apply at once
+#[derive(Clone, Default)]
+pub struct Maintenance(Arc<RwLock<Store>>);
+
+impl Maintenance {
+    /// Parses the windows in a config, to be put in place with `configure` once the rest of the config has loaded
+    pub fn parse(configs: Vec<Config>) -> Result<Vec<Window>> {
+        configs.into_iter().map(Window::new).collect()
+    }
+
+    /// Replaces the windows from the config, leaving those added at runtime
+    pub fn configure(&self, windows: Vec<Window>) {
+        self.0.write().expect("maintenance lock is not poisoned").configured = windows;
+    }
+
+    /// Adds a window at runtime, given the same way as in the config, returning its ID
+    pub fn add(&self, definition: Value) -> Result<u64> {
+        let config: Config = serde_json::from_value(definition.clone()).wrap_err("Invalid maintenance window")?;
+        let window = Window::new(config)?;
+
+        let mut windows = self.0.write().expect("maintenance lock is not poisoned");
+        windows.next_id += 1;
+        let id = windows.next_id;
+        windows.runtime.push(RuntimeWindow { id, definition, window });
+
+        Ok(id)
+    }
+
+    /// Removes a window added at runtime, returning whether there was one with the ID
+    pub fn remove(&self, id: u64) -> bool {
+        let mut windows = self.0.write().expect("maintenance lock is not poisoned");
+        let before = windows.runtime.len();
+        windows.runtime.retain(|runtime| runtime.id != id);
+
+        windows.runtime.len() != before
+    }
+
+    /// The windows added at runtime, as they were given with their ID added
+    pub fn list(&self) -> Vec<Value> {
+        let windows = self.0.read().expect("maintenance lock is not poisoned");
+        windows
+            .runtime
+            .iter()
+            .map(|runtime| {
+                let mut definition = runtime.definition.clone();
+                if let Value::Object(ref mut fields) = definition {
+                    fields.insert("id".to_owned(), runtime.id.into());
+                }
+                definition
+            })
+            .collect()
+    }
+
+    /// The windows which apply to a check with the given labels, now and as they change
+    pub fn for_labels(&self, labels: &HashMap<String, String>) -> Windows {
+        Windows {
+            maintenance: self.clone(),
+            labels: labels.clone(),
+        }
+    }
+}
+
+/// The maintenance windows which apply to a single check
+#[derive(Clone, Default)]
+pub struct Windows {
+    maintenance: Maintenance,
+    labels: HashMap<String, String>,
+}
+
+impl Windows {
+    /// The mode of the strictest window active at the given time, if any
+    pub fn active(&self, now: OffsetDateTime) -> Option<Mode> {
+        let windows = self.maintenance.0.read().expect("maintenance lock is not poisoned");
+        let active: Vec<Mode> = windows
+            .configured
+            .iter()
+            .chain(windows.runtime.iter().map(|runtime| &runtime.window))
+            .filter(|window| window.applies(&self.labels, now))
+            .map(|window| window.mode)
+            .collect();
+
+        if active.contains(&Mode::Skip) {
+            return Some(Mode::Skip);
+        }
+
+        active.first().copied()
+    }
+
+    /// When every skip window active at the given time will have ended, if any is active
+    pub fn skipped_until(&self, now: OffsetDateTime) -> Option<OffsetDateTime> {
+        let windows = self.maintenance.0.read().expect("maintenance lock is not poisoned");
+        windows
+            .configured
+            .iter()
+            .chain(windows.runtime.iter().map(|runtime| &runtime.window))
+            .filter(|window| window.mode == Mode::Skip && window.selects(&self.labels))
+            .filter_map(|window| window.schedule.end(now))
+            .max()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use time::macros::datetime;
+
+    use super::*;
+
+    fn weekly(days: Vec<Day>, start: &str, end: &str) -> Schedule {
+        Schedule::Weekly {
+            days,
+            start: start.to_owned().try_into().unwrap(),
+            end: end.to_owned().try_into().unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_time_of_day() {
+        assert!(TimeOfDay::try_from("02:30".to_owned()).is_ok());
+        assert!(TimeOfDay::try_from("24:00".to_owned()).is_err());
+        assert!(TimeOfDay::try_from("0230".to_owned()).is_err());
+    }
+
+    #[test]
+    fn test_once() {
+        let schedule = Schedule::Once {
+            start: datetime!(2024-03-01 10:00 UTC),
+            end: datetime!(2024-03-01 11:00 UTC),
+        };
+
+        assert!(schedule.contains(datetime!(2024-03-01 10:30 UTC)));
+        assert!(!schedule.contains(datetime!(2024-03-01 11:00 UTC)));
+    }
+
+    #[test]
+    fn test_once_ending_before_it_starts() {
+        for end in ["2024-03-01T09:00:00Z", "2024-03-01T10:00:00Z"] {
+            let config: Config = serde_json::from_value(serde_json::json!({
+                "once": { "start": "2024-03-01T10:00:00Z", "end": end },
+            }))
+            .unwrap();
+            assert!(
+                Maintenance::parse(vec![config]).is_err(),
+                "window ending at {} should be rejected",
+                end
+            );
+        }
+    }
+
+    #[test]
+    fn test_weekly() {
+        // 2024-03-02 is a Saturday
+        let schedule = weekly(vec![Day::Sat], "02:00", "04:00");
+
+        assert!(schedule.contains(datetime!(2024-03-02 03:00 UTC)));
+        assert!(!schedule.contains(datetime!(2024-03-02 04:00 UTC)));
+        assert!(!schedule.contains(datetime!(2024-03-03 03:00 UTC)));
+        assert!(schedule.contains(datetime!(2024-03-02 05:00 +02:00)));
+    }
+
+    #[test]
+    fn test_weekly_past_midnight() {
+        let schedule = weekly(vec![Day::Sat], "23:00", "01:00");
+
+        assert!(schedule.contains(datetime!(2024-03-02 23:30 UTC)));
+        assert!(schedule.contains(datetime!(2024-03-03 00:30 UTC)));
+        assert!(!schedule.contains(datetime!(2024-03-02 00:30 UTC)));
+
+        assert_eq!(
+            schedule.end(datetime!(2024-03-02 23:30 UTC)),
+            Some(datetime!(2024-03-03 01:00 UTC))
+        );
+        assert_eq!(
+            schedule.end(datetime!(2024-03-03 00:30 UTC)),
+            Some(datetime!(2024-03-03 01:00 UTC))
+        );
+    }
+
+    #[test]
+    fn test_skipped_until() {
+        let now = datetime!(2024-03-02 03:00 UTC);
+        let labels = HashMap::from([("hostname".to_owned(), "web-1".to_owned())]);
+        let window =
+            |mode: &str, end: &str| json!({ "mode": mode, "once": { "start": "2024-03-02T00:00:00Z", "end": end } });
+
+        let maintenance = Maintenance::default();
+        let windows = maintenance.for_labels(&labels);
+        assert_eq!(windows.skipped_until(now), None);
+
+        // Silence windows don't stop checks running, and overlapping skip windows skip until they've all ended
+        maintenance.add(window("silence", "2024-03-02T09:00:00Z")).unwrap();
+        assert_eq!(windows.skipped_until(now), None);
+        maintenance.add(window("skip", "2024-03-02T04:00:00Z")).unwrap();
+        maintenance.add(window("skip", "2024-03-02T05:00:00Z")).unwrap();
+        assert_eq!(windows.skipped_until(now), Some(datetime!(2024-03-02 05:00 UTC)));
+        assert_eq!(windows.skipped_until(datetime!(2024-03-02 06:00 UTC)), None);
+    }
+
+    #[test]
+    fn test_active_mode() {
+        let now = OffsetDateTime::now_utc();
+        let window = |select: Option<&str>, mode: &str| json!({ "select": select, "mode": mode, "once": { "start": "2024-01-01T00:00:00Z", "end": "2999-01-01T00:00:00Z" } });
+        let labels = HashMap::from([("hostname".to_owned(), "web-1".to_owned())]);
+
+        let maintenance = Maintenance::default();
+        let windows = maintenance.for_labels(&labels);
+        assert_eq!(windows.active(now), None);
+
+        let configs = vec![serde_json::from_value(window(None, "silence")).unwrap()];
+        maintenance.configure(Maintenance::parse(configs).unwrap());
+        assert_eq!(windows.active(now), Some(Mode::Silence));
+
+        // Windows added at runtime apply to checks straight away, with the strictest mode winning
+        let other = maintenance.add(window(Some("hostname:web-2"), "skip")).unwrap();
+        assert_eq!(windows.active(now), Some(Mode::Silence));
+        let skip = maintenance.add(window(Some("hostname:web-1"), "skip")).unwrap();
+        assert_eq!(windows.active(now), Some(Mode::Skip));
+        assert_eq!(maintenance.list()[1]["id"], skip);
+
+        // Reloading the config leaves them in place
+        maintenance.configure(Vec::new());
+        assert_eq!(windows.active(now), Some(Mode::Skip));
+
+        assert!(maintenance.remove(skip));
+        assert!(!maintenance.remove(skip));
+        assert_eq!(windows.active(now), None);
+        assert_eq!(maintenance.list().len(), 1);
+        assert_eq!(maintenance.list()[0]["id"], other);
+
+        assert!(maintenance.add(json!({ "mode": "skip" })).is_err());
+    }
+}
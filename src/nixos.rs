@@ -0,0 +1,339 @@
+use serde_json::{Map, Value};
+
+use crate::config;
+
+/// Defaults which only make sense within a NixOS module, as (check type, param, Nix expression)
+const NIX_DEFAULTS: &[(&str, &str, &str)] = &[("ssh", "hostname", "config.networking.hostName")];
+
+/// The helpers for a list of submodules told apart by their `type` option
+const TAGGED_SUBMODULES: &str = r#"      # adapted from https://gist.github.com/GrafBlutwurst/2d6156321d6b89cb21a1d0702f5d853e
+      addTag =
+        typeTag: module:
+        let
+          tagModule = {
+            type = mkOption {
+              type = types.enum [ typeTag ];
+              description = ''Type Tag (${typeTag})'';
+            };
+          };
+          imports = if module ? imports then { inherit (module) imports; } else { };
+          config = if module ? config then { inherit (module) config; } else { };
+        in
+        (
+          assert (builtins.isAttrs module) || abort "Module passed to oneOfTagged must be a Record";
+          assert (builtins.isString typeTag) || abort "TypeTag passed to oneOfTagged must be a String";
+          assert
+            !(module ? option.type)
+            || abort "Module passed to oneOfTagged canno't have an option called type (reserved for typetag)";
+          imports // config // { options = module.options // tagModule; }
+        );
+
+      taggedSubmodule =
+        typeTag: module:
+        let
+          taggedModule = addTag typeTag module;
+          baseSubmodule = types.submodule taggedModule;
+          check = v: (baseSubmodule.check v) && (v.type == typeTag);
+          description = "Submodule[${typeTag}]";
+        in
+        types.addCheck (
+        baseSubmodule
+        // {
+          inherit description;
+        }) check;
+
+      mapAttrDefs = definitions: attrValues (mapAttrs taggedSubmodule definitions);
+
+      oneOfTagged = definitions: types.oneOf (mapAttrDefs definitions);
+"#;
+
+fn indent(level: usize) -> String {
+    "  ".repeat(level)
+}
+
+fn nix_string(s: &str) -> String {
+    format!(
+        "\"{}\"",
+        s.replace('\\', "\\\\").replace('"', "\\\"").replace("${", "\\${")
+    )
+}
+
+fn nix_name(name: &str) -> String {
+    let identifier = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || "_-'".contains(c));
+
+    if identifier {
+        name.to_owned()
+    } else {
+        nix_string(name)
+    }
+}
+
+/// Renders a JSON value as a Nix value, laid out like the rest of options.nix
+fn nix_value(value: &Value, level: usize) -> String {
+    match value {
+        Value::Null => "null".to_owned(),
+        Value::Bool(b) => b.to_string(),
+        Value::Number(n) => n.to_string(),
+        Value::String(s) => nix_string(s),
+        Value::Array(items) if items.is_empty() => "[ ]".to_owned(),
+        Value::Array(items) => {
+            let items: Vec<String> = items.iter().map(|item| nix_value(item, level + 1)).collect();
+            format!("[ {} ]", items.join(" "))
+        }
+        Value::Object(fields) if fields.is_empty() => "{ }".to_owned(),
+        Value::Object(fields) => {
+            let mut out = "{\n".to_owned();
+            for (name, value) in fields {
+                out += &format!(
+                    "{}{} = {};\n",
+                    indent(level + 1),
+                    nix_name(name),
+                    nix_value(value, level + 1)
+                );
+            }
+            out + &indent(level) + "}"
+        }
+    }
+}
+
+/// Wraps a type in parentheses when it's passed to a type function
+fn argument(nix_type: String) -> String {
+    if nix_type.contains(' ') {
+        format!("({})", nix_type)
+    } else {
+        nix_type
+    }
+}
+
+struct Generator<'a> {
+    definitions: &'a Map<String, Value>,
+}
+
+impl Generator<'_> {
+    /// Follows `$ref`s, including those schemars wraps in an `allOf` to attach a description or default
+    fn resolve<'s>(&'s self, schema: &'s Value) -> &'s Value {
+        if let Some(reference) = schema["$ref"].as_str() {
+            let name = reference.trim_start_matches("#/definitions/");
+            return self.resolve(&self.definitions[name]);
+        }
+
+        match schema["allOf"].as_array().map(Vec::as_slice) {
+            Some([only]) => self.resolve(only),
+            _ => schema,
+        }
+    }
+
+    fn nix_type(&self, schema: &Value, level: usize) -> String {
+        let schema = self.resolve(schema);
+
+        if let Some(variants) = schema["anyOf"].as_array() {
+            let not_null: Vec<&Value> = variants.iter().filter(|v| v["type"] != "null").collect();
+            return match not_null.as_slice() {
+                [only] if not_null.len() < variants.len() => {
+                    format!("types.nullOr {}", argument(self.nix_type(only, level)))
+                }
+                _ => "types.anything".to_owned(),
+            };
+        }
+
+        if let Some(values) = schema["enum"].as_array() {
+            let values: Vec<String> = values.iter().map(|value| nix_value(value, level)).collect();
+            return format!("types.enum [ {} ]", values.join(" "));
+        }
+
+        // Enums with documented variants are a oneOf of single values
+        if let Some(variants) = schema["oneOf"].as_array() {
+            let values: Option<Vec<String>> = variants
+                .iter()
+                .map(|variant| match variant["enum"].as_array().map(Vec::as_slice) {
+                    Some([only]) => Some(nix_value(only, level)),
+                    _ => variant.get("const").map(|only| nix_value(only, level)),
+                })
+                .collect();
+            if let Some(values) = values {
+                return format!("types.enum [ {} ]", values.join(" "));
+            }
+        }
+
+        let types: Vec<&str> = match schema["type"] {
+            Value::String(ref t) => vec![t.as_str()],
+            Value::Array(ref ts) => ts.iter().filter_map(Value::as_str).collect(),
+            _ => Vec::new(),
+        };
+        let nullable = types.contains(&"null");
+
+        let nix_type = match types.iter().find(|t| **t != "null").copied() {
+            Some("string") => "types.str".to_owned(),
+            Some("integer") => "types.int".to_owned(),
+            Some("number") => "types.number".to_owned(),
+            Some("boolean") => "types.bool".to_owned(),
+            Some("array") => format!("types.listOf {}", argument(self.nix_type(&schema["items"], level))),
+            Some("object") if schema.get("oneOf").is_none() && schema["properties"].is_object() => format!(
+                "types.submodule {{\n{}options = {};\n{}}}",
+                indent(level + 1),
+                self.options(schema, None, level + 1),
+                indent(level)
+            ),
+            Some("object") if schema["additionalProperties"].is_object() => format!(
+                "types.attrsOf {}",
+                argument(self.nix_type(&schema["additionalProperties"], level))
+            ),
+            Some("object") => "types.attrs".to_owned(),
+            _ => "types.anything".to_owned(),
+        };
+
+        if nullable {
+            format!("types.nullOr {}", argument(nix_type))
+        } else {
+            nix_type
+        }
+    }
+
+    fn option(&self, name: &str, schema: &Value, required: bool, nix_default: Option<&str>, level: usize) -> String {
+        let mut nix_type = self.nix_type(schema, level + 1);
+        let default = match (nix_default, schema.get("default")) {
+            (Some(nix_default), _) => Some(nix_default.to_owned()),
+            (None, Some(default)) => Some(nix_value(default, level + 1)),
+            // Optional fields without a default are left out of the generated config, via null
+            (None, None) if !required => {
+                if !nix_type.starts_with("types.nullOr") {
+                    nix_type = format!("types.nullOr {}", argument(nix_type));
+                }
+                Some("null".to_owned())
+            }
+            (None, None) => None,
+        };
+
+        let mut out = format!("{}{} = mkOption {{\n", indent(level), nix_name(name));
+        out += &format!("{}type = {};\n", indent(level + 1), nix_type);
+        if let Some(default) = default {
+            out += &format!("{}default = {};\n", indent(level + 1), default);
+        }
+        if let Some(description) = schema["description"].as_str() {
+            out += &format!("{}description = {};\n", indent(level + 1), nix_string(description));
+        }
+        out + &indent(level) + "};\n"
+    }
+
+    /// Renders an object schema's properties as an attrset of options, for a check's params if given its type
+    fn options(&self, schema: &Value, check_type: Option<&str>, level: usize) -> String {
+        let schema = self.resolve(schema);
+        let required: Vec<&str> = schema["required"]
+            .as_array()
+            .map(|names| names.iter().filter_map(Value::as_str).collect())
+            .unwrap_or_default();
+
+        let Some(properties) = schema["properties"].as_object() else {
+            return "{ }".to_owned();
+        };
+
+        let mut out = "{\n".to_owned();
+        for (name, property) in properties {
+            let nix_default = NIX_DEFAULTS
+                .iter()
+                .find(|(for_type, param, _)| Some(*for_type) == check_type && param == name)
+                .map(|(_, _, default)| *default);
+
+            out += &self.option(
+                name,
+                property,
+                required.contains(&name.as_str()),
+                nix_default,
+                level + 1,
+            );
+        }
+        out + &indent(level) + "}"
+    }
+
+    fn check_types(&self) -> String {
+        let definition = &self.definitions["CheckDefinition"];
+        let mut out = String::new();
+
+        for variant in definition["oneOf"].as_array().into_iter().flatten() {
+            let check_type = variant["properties"]["type"]["enum"][0].as_str().unwrap_or_default();
+            out += &format!("        {} = {{\n", check_type);
+            out += "          options = {\n";
+            out += &format!(
+                "            params = {};\n",
+                self.options(&variant["properties"]["params"], Some(check_type), 6)
+            );
+            out += "          } // checkDefinitionCommon;\n";
+            out += "        };\n";
+        }
+
+        out
+    }
+}
+
+/// Generates `options.nix`, the NixOS module defining `deployment.healthchecks`, from the config schema
+pub fn options() -> String {
+    let schema = serde_json::to_value(config::schema()).expect("schemas serialize to JSON");
+    let definitions = schema["definitions"].as_object().expect("schema has definitions");
+    let generator = Generator { definitions };
+
+    let mut out = "# Generated by `colmena-health nixos-options`; don't edit by hand\n".to_owned();
+    out += "{ lib, config, ... }:\n{\n  options =\n    with lib;\n    let\n";
+    out += TAGGED_SUBMODULES;
+    out += "\n";
+    out += &format!(
+        "      checkDefinitionCommon = {};\n\n",
+        generator.options(&definitions["CheckDefinition"], None, 3)
+    );
+    out += "      checkDefinition = oneOfTagged {\n";
+    out += &generator.check_types();
+    out += "      };\n";
+    out += "    in\n    {\n";
+    out += "      deployment.healthchecks = lib.mkOption {\n";
+    out += "        type = with lib; types.listOf checkDefinition;\n";
+    out += "        default = [ ];\n";
+    out += "      };\n";
+    out += "    };\n}\n";
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_nix_value() {
+        assert_eq!(nix_value(&json!([1, "a${b}", null]), 0), r#"[ 1 "a\${b}" null ]"#);
+        assert_eq!(nix_value(&json!({}), 0), "{ }");
+        assert_eq!(
+            nix_value(&json!({ "maxRetries": 3, "my.label": true }), 1),
+            "{\n    maxRetries = 3;\n    \"my.label\" = true;\n  }"
+        );
+    }
+
+    #[test]
+    fn test_documented_enum() {
+        let definitions = Map::new();
+        let generator = Generator {
+            definitions: &definitions,
+        };
+        let schema = json!({
+            "oneOf": [
+                { "description": "Always", "type": "string", "enum": ["none"] },
+                { "description": "Sometimes", "type": "string", "enum": ["full"] },
+                { "const": "equal" },
+            ],
+        });
+
+        assert_eq!(
+            generator.nix_type(&schema, 0),
+            r#"types.enum [ "none" "full" "equal" ]"#
+        );
+    }
+
+    #[test]
+    fn test_options_nix_up_to_date() {
+        assert!(
+            include_str!("../options.nix") == options(),
+            "options.nix is out of date, regenerate it with `colmena-health nixos-options > options.nix`"
+        );
+    }
+}
@@ -0,0 +1,1088 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use futures::future::join_all;
+use log::{error, info, warn};
+use schemars::JsonSchema;
+use serde::Deserialize;
+use simple_eyre::eyre::Result;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::task::JoinHandle;
+use tokio::time::{interval, Instant, MissedTickBehavior};
+
+use crate::alert::Config as AlertConfig;
+use crate::maintenance::Mode;
+use crate::{alertmanager, email, template, webhook, CheckInfo, CheckStatus, CheckUpdate};
+
+#[derive(Clone, Debug)]
+pub struct Alert {
+    /// The ID of the check this alert is about, if any
+    pub id: Option<usize>,
+    pub name: String,
+    pub starts_at: OffsetDateTime,
+    pub ends_at: Option<OffsetDateTime>,
+    pub labels: HashMap<String, String>,
+    pub annotations: HashMap<String, String>,
+}
+
+impl Alert {
+    fn new(
+        id: Option<usize>,
+        name: String,
+        labels: HashMap<String, String>,
+        annotations: HashMap<String, String>,
+    ) -> Self {
+        Alert {
+            id,
+            name,
+            starts_at: OffsetDateTime::now_utc(),
+            ends_at: None,
+            labels,
+            annotations,
+        }
+    }
+
+    fn resolve(&mut self) {
+        self.ends_at = Some(OffsetDateTime::now_utc());
+    }
+
+    pub fn is_resolved(&self) -> bool {
+        self.ends_at.is_some()
+    }
+
+    /// Looks up a template variable describing this alert
+    pub fn lookup(&self, key: &str) -> Option<String> {
+        let format_time = |t: OffsetDateTime| t.format(&Rfc3339).ok();
+
+        match key {
+            "id" => self.id.map(|id| id.to_string()),
+            "name" => Some(self.name.clone()),
+            "status" => Some(if self.is_resolved() { "resolved" } else { "firing" }.to_owned()),
+            "startsAt" => format_time(self.starts_at),
+            "endsAt" => self.ends_at.and_then(format_time),
+            _ => {
+                if let Some(label) = key.strip_prefix("labels.") {
+                    self.labels.get(label).cloned()
+                } else if let Some(annotation) = key.strip_prefix("annotations.") {
+                    self.annotations.get(annotation).cloned()
+                } else {
+                    None
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Severity {
+    Warning,
+    Critical,
+}
+
+impl Severity {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Severity::Warning => "warning",
+            Severity::Critical => "critical",
+        }
+    }
+}
+
+/// The ongoing failure of a check
+struct Failure {
+    since: OffsetDateTime,
+    /// Every run which didn't pass since the failure began, whether it failed or warned
+    attempts: u32,
+    last_error: Option<String>,
+    /// Consecutive failed runs, towards a critical alert
+    failures: u32,
+    /// Consecutive runs which passed with a warning, towards a warning alert
+    warnings: u32,
+    /// Consecutive successes since the alert for this failure fired
+    successes: u32,
+    /// The severity of the alert firing for this failure, if any
+    alerted: Option<Severity>,
+}
+
+/// Looks up a template variable describing a check and its current failure, if any
+fn check_lookup(id: usize, info: &CheckInfo, failure: Option<&Failure>, key: &str) -> Option<String> {
+    match key {
+        "id" => Some(id.to_string()),
+        "name" => Some(info.name.clone()),
+        "type" => Some(info.check_type.clone()),
+        "instance" => Some(info.instance.clone()),
+        "error" => failure.and_then(|failure| failure.last_error.clone()),
+        "attempts" => failure.map(|failure| failure.attempts.to_string()),
+        "failureStart" => failure.and_then(|failure| failure.since.format(&Rfc3339).ok()),
+        "duration" => failure.map(|failure| {
+            let elapsed = OffsetDateTime::now_utc() - failure.since;
+            template::format_duration(elapsed.try_into().unwrap_or_default())
+        }),
+        _ => {
+            if let Some(label) = key.strip_prefix("labels.") {
+                info.labels.get(label).cloned()
+            } else if let Some(param) = key.strip_prefix("params.") {
+                match info.params.pointer(&format!("/{}", param.replace('.', "/")))? {
+                    serde_json::Value::String(value) => Some(value.clone()),
+                    value => Some(value.to_string()),
+                }
+            } else {
+                None
+            }
+        }
+    }
+}
+
+/// Builds an alert about a check, rendering any templates in its labels and annotations
+///
+/// The check's own labels take precedence over the automatic `alertname`, `check_type` and `instance` labels.
+fn check_alert(id: usize, info: &CheckInfo, failure: Option<&Failure>, alertname: &str, severity: Severity) -> Alert {
+    let lookup = |key: &str| check_lookup(id, info, failure, key);
+    let render = |map: &HashMap<String, String>| -> HashMap<String, String> {
+        map.iter()
+            .map(|(name, value)| (name.clone(), template::render(value, lookup, str::to_owned)))
+            .collect()
+    };
+
+    let mut labels = HashMap::from([
+        ("alertname".to_owned(), alertname.to_owned()),
+        ("check_type".to_owned(), info.check_type.clone()),
+        ("instance".to_owned(), info.instance.clone()),
+        ("severity".to_owned(), severity.as_str().to_owned()),
+    ]);
+    labels.extend(render(&info.labels));
+
+    Alert::new(Some(id), info.name.clone(), labels, render(&info.annotations))
+}
+
+#[async_trait]
+pub trait Notifier: Send + Sync {
+    fn name(&self) -> String;
+    /// Called whenever an alert starts firing or is resolved
+    async fn notify(&self, alert: &Alert);
+    /// Called every `realertInterval` with every alert that is still firing
+    async fn refresh(&self, _alerts: &[&Alert]) {}
+    /// Called in the background every `realertInterval` to update any silences kept upstream
+    async fn sync_silences(&self) {}
+    /// Whether alerts with these labels are silenced upstream, as of the last sync
+    fn silenced(&self, _labels: &HashMap<String, String>) -> Option<Mode> {
+        None
+    }
+    /// Called once when the dispatcher stops, to deliver anything still pending
+    async fn shutdown(&self) {}
+}
+
+/// A check being started or stopped when the config is reloaded
+pub enum RegistryChange {
+    Add(usize, Box<CheckInfo>),
+    Remove(usize),
+}
+
+/// The IDs of checks which shouldn't run, because their alerts are silenced upstream
+pub type SkippedChecks = Arc<Mutex<HashSet<usize>>>;
+
+#[derive(Clone, Deserialize, JsonSchema, Debug)]
+#[schemars(rename = "NotifierConfig")]
+#[serde(tag = "type", content = "params", rename_all = "lowercase")]
+pub enum Config {
+    Alertmanager(alertmanager::Config),
+    Email(email::Config),
+    Webhook(webhook::Config),
+}
+
+impl Config {
+    pub fn into_notifier(self) -> Result<Box<dyn Notifier>> {
+        Ok(match self {
+            Config::Alertmanager(am_config) => Box::new(alertmanager::AlertManagerClient::new(am_config)?),
+            Config::Email(email_config) => Box::new(email::Notifier::new(email_config)?),
+            Config::Webhook(webhook_config) => Box::new(webhook::Notifier::new(webhook_config)?),
+        })
+    }
+}
+
+fn watchdog_alert(alert_config: &AlertConfig) -> Option<Alert> {
+    let watchdog = alert_config.watchdog.as_ref()?;
+
+    let mut labels = HashMap::from([("alertname".to_owned(), "Watchdog".to_owned())]);
+    labels.extend(watchdog.labels.clone());
+
+    Some(Alert::new(None, "watchdog".to_owned(), labels, HashMap::new()))
+}
+
+/// Fetches upstream silences every `period` in the background, so a slow notifier never holds up the dispatcher,
+/// and lets it know each time they've been fetched
+async fn sync_silences_every(notifiers: Vec<Arc<dyn Notifier>>, period: Duration, synced: UnboundedSender<()>) {
+    let mut interval = interval(period);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    // Silences were already fetched before the dispatcher started
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+        join_all(notifiers.iter().map(|notifier| notifier.sync_silences())).await;
+        if synced.send(()).is_err() {
+            return;
+        }
+    }
+}
+
+/// Something for a notifier to deliver
+enum Delivery {
+    Notify(Alert),
+    Refresh(Vec<Alert>),
+}
+
+/// A notifier with a queue and task of its own, so a slow or unreachable notifier holds up neither the dispatcher
+/// nor any other notifier
+struct Queue {
+    notifier: Arc<dyn Notifier>,
+    deliveries: UnboundedSender<Delivery>,
+    task: JoinHandle<()>,
+}
+
+impl Queue {
+    /// Starts the queue's task, so this must be called within a tokio runtime
+    fn new(notifier: Box<dyn Notifier>) -> Self {
+        let notifier: Arc<dyn Notifier> = Arc::from(notifier);
+        let (deliveries, mut rx) = unbounded_channel();
+
+        let task = tokio::spawn({
+            let notifier = notifier.clone();
+            async move {
+                while let Some(delivery) = rx.recv().await {
+                    match delivery {
+                        Delivery::Notify(alert) => notifier.notify(&alert).await,
+                        Delivery::Refresh(alerts) => notifier.refresh(&alerts.iter().collect::<Vec<_>>()).await,
+                    }
+                }
+
+                notifier.shutdown().await;
+            }
+        });
+
+        Queue {
+            notifier,
+            deliveries,
+            task,
+        }
+    }
+
+    fn send(&self, delivery: Delivery) {
+        if self.deliveries.send(delivery).is_err() {
+            error!("Unable to queue alert for {}, which has stopped", self.notifier.name());
+        }
+    }
+
+    /// Waits for everything queued to be delivered
+    async fn close(self) {
+        drop(self.deliveries);
+        if let Err(e) = self.task.await {
+            error!("Notifier {} stopped unexpectedly: {}", self.notifier.name(), e);
+        }
+    }
+}
+
+/// Tracks check updates, turning them into alerts which are handed to every configured notifier
+pub struct Dispatcher {
+    active_alerts: HashMap<usize, Alert>,
+    alert_config: AlertConfig,
+    changes: UnboundedReceiver<RegistryChange>,
+    failures: HashMap<usize, Failure>,
+    flapping_alerts: HashMap<usize, Alert>,
+    /// Recent results per check, used for flap detection
+    history: HashMap<usize, VecDeque<Option<Severity>>>,
+    /// When each check last sent an update, and how long it then said it would wait before running again
+    last_seen: HashMap<usize, (Instant, Duration)>,
+    notifiers: Vec<Queue>,
+    registry: HashMap<usize, CheckInfo>,
+    skipped: SkippedChecks,
+    stale_alerts: HashMap<usize, Alert>,
+    updates: UnboundedReceiver<CheckUpdate>,
+    /// The always-firing heartbeat alert, if enabled, which keeps the time it started
+    watchdog: Option<Alert>,
+}
+
+impl Dispatcher {
+    pub fn new(
+        alert_config: AlertConfig,
+        registry: HashMap<usize, CheckInfo>,
+        skipped: SkippedChecks,
+        updates: UnboundedReceiver<CheckUpdate>,
+        changes: UnboundedReceiver<RegistryChange>,
+    ) -> Result<Self> {
+        let now = Instant::now();
+
+        if alert_config.base_url.is_some() {
+            warn!("alerting.baseURL is deprecated, use an alertmanager notifier instead");
+        }
+
+        let notifiers = alert_config
+            .all_notifiers()
+            .into_iter()
+            .map(|config| config.into_notifier().map(Queue::new))
+            .collect::<Result<_>>()?;
+
+        Ok(Dispatcher {
+            active_alerts: HashMap::new(),
+            changes,
+            failures: HashMap::new(),
+            flapping_alerts: HashMap::new(),
+            history: HashMap::new(),
+            last_seen: registry.keys().map(|id| (*id, (now, Duration::ZERO))).collect(),
+            notifiers,
+            registry,
+            skipped,
+            stale_alerts: HashMap::new(),
+            updates,
+            watchdog: watchdog_alert(&alert_config),
+            alert_config,
+        })
+    }
+
+    fn notify(&self, alert: &Alert) {
+        for queue in self.notifiers.iter() {
+            queue.send(Delivery::Notify(alert.clone()));
+        }
+    }
+
+    /// Renders the templates in checks' annotations again, so values like `{{ duration }}` stay current
+    ///
+    /// Labels identify an alert, so they keep the values they had when it fired. The automatic `output` annotation
+    /// describes the run which fired the alert, so it's left alone too.
+    fn render_annotations(&mut self) {
+        for alerts in [
+            &mut self.active_alerts,
+            &mut self.flapping_alerts,
+            &mut self.stale_alerts,
+        ] {
+            for (id, alert) in alerts.iter_mut() {
+                let Some(info) = self.registry.get(id) else {
+                    continue;
+                };
+                let lookup = |key: &str| check_lookup(*id, info, self.failures.get(id), key);
+
+                for (name, value) in info.annotations.iter().filter(|(name, _)| *name != "output") {
+                    alert
+                        .annotations
+                        .insert(name.clone(), template::render(value, lookup, str::to_owned));
+                }
+            }
+        }
+    }
+
+    fn refresh(&mut self) {
+        self.render_annotations();
+
+        let alerts: Vec<Alert> = self
+            .active_alerts
+            .values()
+            .chain(self.flapping_alerts.values())
+            .chain(self.stale_alerts.values())
+            .chain(self.watchdog.iter())
+            .cloned()
+            .collect();
+
+        if alerts.is_empty() {
+            return;
+        }
+
+        for queue in self.notifiers.iter() {
+            queue.send(Delivery::Refresh(alerts.clone()));
+        }
+    }
+
+    /// The strictest upstream silence matching the alert a check would fire
+    fn silenced(&self, id: usize, info: &CheckInfo, severity: Severity) -> Option<Mode> {
+        let alert = check_alert(id, info, self.failures.get(&id), "HealthCheckFailed", severity);
+        let modes: Vec<Mode> = self
+            .notifiers
+            .iter()
+            .filter_map(|queue| queue.notifier.silenced(&alert.labels))
+            .collect();
+
+        if modes.contains(&Mode::Skip) {
+            return Some(Mode::Skip);
+        }
+
+        modes.first().copied()
+    }
+
+    /// Fetches upstream silences and updates which checks should be skipped, before the dispatcher starts
+    pub async fn sync_silences(&self) {
+        join_all(self.notifiers.iter().map(|queue| queue.notifier.sync_silences())).await;
+        self.update_skipped();
+    }
+
+    /// Updates which checks should be skipped from the silences as of the last sync
+    fn update_skipped(&self) {
+        let skipped = self
+            .registry
+            .iter()
+            .filter(|(id, info)| self.silenced(**id, info, Severity::Critical) == Some(Mode::Skip))
+            .map(|(id, _)| *id)
+            .collect();
+
+        *self.skipped.lock().expect("skipped checks lock is not poisoned") = skipped;
+    }
+
+    async fn process_update(&mut self, update: CheckUpdate) {
+        if !self.registry.contains_key(&update.id) {
+            error!(
+                "Received an update for id {}, which was not in the registry; skipping alerting",
+                update.id
+            );
+            return;
+        }
+
+        let waiting = match update.status {
+            CheckStatus::Waiting(duration, ..) | CheckStatus::Retrying(_, duration) => duration,
+            _ => Duration::ZERO,
+        };
+        // A queued check can't send updates until another finishes, however long that takes, so it isn't stale
+        if let CheckStatus::Queued = update.status {
+            self.last_seen.remove(&update.id);
+        } else {
+            self.last_seen.insert(update.id, (Instant::now(), waiting));
+        }
+
+        if let Some(mut alert) = self.stale_alerts.remove(&update.id) {
+            alert.resolve();
+            info!("Check reporting again: {:?}", alert.labels);
+
+            self.notify(&alert);
+        }
+
+        let severity = match update.status {
+            CheckStatus::Failed => Some(Severity::Critical),
+            CheckStatus::Warning => Some(Severity::Warning),
+            CheckStatus::Succeeded => None,
+            _ => return,
+        };
+
+        let Some(info) = self.registry.get(&update.id) else {
+            return;
+        };
+        let policy = &info.alert_policy;
+
+        if let Some(severity) = severity {
+            let failure = self.failures.entry(update.id).or_insert_with(|| Failure {
+                since: OffsetDateTime::now_utc(),
+                attempts: 0,
+                last_error: None,
+                failures: 0,
+                warnings: 0,
+                successes: 0,
+                alerted: None,
+            });
+            failure.attempts += 1;
+            failure.last_error = update.msg.clone();
+            failure.successes = 0;
+            match severity {
+                Severity::Critical => {
+                    failure.failures += 1;
+                    failure.warnings = 0;
+                }
+                Severity::Warning => {
+                    failure.warnings += 1;
+                    failure.failures = 0;
+                }
+            }
+        } else if let Some(failure) = self.failures.get_mut(&update.id) {
+            failure.successes += 1;
+            failure.failures = 0;
+            failure.warnings = 0;
+        }
+
+        if let Some(ref flap_detection) = policy.flap_detection {
+            let history = self.history.entry(update.id).or_default();
+            history.push_back(severity);
+            if history.len() > flap_detection.window {
+                history.pop_front();
+            }
+
+            let changes = history
+                .iter()
+                .zip(history.iter().skip(1))
+                .filter(|(a, b)| a != b)
+                .count();
+            let runs = history.len();
+            if changes > flap_detection.threshold {
+                // A single flapping alert replaces the usual churn of firing and resolving
+                if !self.flapping_alerts.contains_key(&update.id) {
+                    if let Some(mut alert) = self.active_alerts.remove(&update.id) {
+                        alert.resolve();
+                        self.notify(&alert);
+                    }
+                    if let Some(failure) = self.failures.get_mut(&update.id) {
+                        failure.alerted = None;
+                    }
+
+                    let mut alert = check_alert(
+                        update.id,
+                        info,
+                        self.failures.get(&update.id),
+                        "HealthCheckFlapping",
+                        Severity::Warning,
+                    );
+                    alert.labels.insert("flapping".to_owned(), "true".to_owned());
+
+                    warn!(
+                        "Check is flapping, having changed state {} times in {} runs - {}",
+                        changes, runs, info.name
+                    );
+                    self.notify(&alert);
+                    self.flapping_alerts.insert(update.id, alert);
+                }
+
+                return;
+            }
+
+            if let Some(mut alert) = self.flapping_alerts.remove(&update.id) {
+                alert.resolve();
+                info!("Check stopped flapping - {}", info.name);
+
+                self.notify(&alert);
+            }
+        }
+
+        if let Some(severity) = severity {
+            if info.maintenance.active(OffsetDateTime::now_utc()).is_some() {
+                info!(
+                    "Check {} during maintenance, not alerting - {}",
+                    severity.as_str(),
+                    info.name
+                );
+                return;
+            }
+
+            let failure = self.failures.get(&update.id).expect("a failing check has a failure");
+            match failure.alerted {
+                Some(alerted) if alerted == severity => return,
+                // A warning isn't a recovery, so the critical alert fires until the check passes again
+                Some(Severity::Critical) => return,
+                _ => {}
+            }
+
+            let streak = match severity {
+                Severity::Critical => failure.failures,
+                Severity::Warning => failure.warnings,
+            };
+            if streak < policy.failures_before_alert {
+                info!(
+                    "Check {} {} of {} times before alerting - {}",
+                    severity.as_str(),
+                    streak,
+                    policy.failures_before_alert,
+                    info.name
+                );
+                return;
+            }
+
+            if self.silenced(update.id, info, severity).is_some() {
+                info!(
+                    "Check {} but matches an Alertmanager silence, not alerting - {}",
+                    severity.as_str(),
+                    info.name
+                );
+                return;
+            }
+
+            // Alerts with a different severity are distinct, so escalating replaces the warning alert entirely
+            if let Some(mut alert) = self.active_alerts.remove(&update.id) {
+                alert.resolve();
+                self.notify(&alert);
+            }
+
+            let failure = self
+                .failures
+                .get_mut(&update.id)
+                .expect("a failing check has a failure");
+            failure.alerted = Some(severity);
+            let mut alert = check_alert(update.id, info, Some(failure), "HealthCheckFailed", severity);
+
+            if self.alert_config.allow_output_annotation {
+                // Combining these ifs is an unstable feature
+                if let Some(ref output) = update.msg {
+                    alert.annotations.insert("output".to_owned(), output.clone());
+                };
+            }
+
+            info!("Check {} - {}", severity.as_str(), info.name);
+            self.notify(&alert);
+            self.active_alerts.insert(update.id, alert);
+        } else {
+            let successes = self.failures.get(&update.id).map(|failure| failure.successes);
+            if self.active_alerts.contains_key(&update.id)
+                && successes.unwrap_or_default() < policy.successes_before_resolve
+            {
+                return;
+            }
+
+            self.failures.remove(&update.id);
+
+            if let Some(mut alert) = self.active_alerts.remove(&update.id) {
+                alert.resolve();
+                info!("Check passing again: {:?}", alert.labels);
+
+                self.notify(&alert);
+            }
+        }
+    }
+
+    /// Forgets a check which was removed from the config, resolving any of its alerts
+    fn remove_check(&mut self, id: usize) {
+        self.registry.remove(&id);
+        self.failures.remove(&id);
+        self.history.remove(&id);
+        self.last_seen.remove(&id);
+
+        let alerts = [
+            self.active_alerts.remove(&id),
+            self.flapping_alerts.remove(&id),
+            self.stale_alerts.remove(&id),
+        ];
+        for mut alert in alerts.into_iter().flatten() {
+            alert.resolve();
+            self.notify(&alert);
+        }
+    }
+
+    async fn process_change(&mut self, change: RegistryChange) {
+        match change {
+            RegistryChange::Add(id, info) => {
+                self.last_seen.insert(id, (Instant::now(), Duration::ZERO));
+                self.registry.insert(id, *info);
+            }
+            RegistryChange::Remove(id) => self.remove_check(id),
+        }
+
+        self.update_skipped();
+    }
+
+    /// Fires an alert for every check whose loop hasn't reported within its allowed window
+    fn find_stale_checks(&mut self) {
+        let Some(ref watchdog) = self.alert_config.watchdog else {
+            return;
+        };
+
+        let mut stale = Vec::new();
+
+        for (id, (seen, waiting)) in self.last_seen.iter() {
+            if self.stale_alerts.contains_key(id) {
+                continue;
+            }
+
+            let Some(info) = self.registry.get(id) else {
+                continue;
+            };
+
+            // Scheduled and retrying checks may announce waits far longer than any interval, and any wait may be
+            // followed by an attempt lasting up to the check's timeout
+            let allowed = (info.alert_policy.longest_wait().max(*waiting) + info.check_timeout)
+                .mul_f64(watchdog.stale_multiplier);
+
+            if seen.elapsed() <= allowed {
+                continue;
+            }
+
+            warn!("Check has not reported in {:?} - {}", seen.elapsed(), info.name);
+
+            let mut alert = check_alert(*id, info, self.failures.get(id), "HealthCheckStale", Severity::Critical);
+            alert.annotations.insert(
+                "output".to_owned(),
+                format!("No update from '{}' in {:?}", info.name, seen.elapsed()),
+            );
+
+            stale.push((*id, alert));
+        }
+
+        for (id, alert) in stale {
+            self.notify(&alert);
+            self.stale_alerts.insert(id, alert);
+        }
+    }
+
+    pub async fn run(mut self) {
+        let mut interval = interval(self.alert_config.realert_interval);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+        let (synced_tx, mut synced) = unbounded_channel();
+        let notifiers = self.notifiers.iter().map(|queue| queue.notifier.clone()).collect();
+        let syncer = tokio::spawn(sync_silences_every(
+            notifiers,
+            self.alert_config.realert_interval,
+            synced_tx,
+        ));
+
+        loop {
+            tokio::select! {
+                // A check's registration always arrives before its first update
+                biased;
+
+                Some(change) = self.changes.recv() => self.process_change(change).await,
+                Some(()) = synced.recv() => self.update_skipped(),
+                _ = interval.tick() => {
+                    self.find_stale_checks();
+                    self.refresh();
+                }
+                update = self.updates.recv() => {
+                    match update {
+                        Some(update) => self.process_update(update).await,
+                        None => {
+                            syncer.abort();
+                            self.refresh();
+                            join_all(self.notifiers.into_iter().map(Queue::close)).await;
+                            return
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use tokio::time::sleep;
+
+    use super::*;
+    use crate::alert::Policy;
+
+    fn check_info(policy: serde_json::Value) -> CheckInfo {
+        CheckInfo {
+            name: "web".to_owned(),
+            check_type: "http".to_owned(),
+            instance: "web-1".to_owned(),
+            params: json!({ "url": "https://web-1/health" }),
+            alert_policy: serde_json::from_value::<Policy>(policy).unwrap(),
+            check_timeout: Duration::from_secs(1),
+            maintenance: Default::default(),
+            labels: HashMap::new(),
+            annotations: HashMap::from([("summary".to_owned(), "{{ attempts }} failed runs".to_owned())]),
+        }
+    }
+
+    /// A dispatcher without notifiers, tracking a single check with ID 1
+    fn dispatcher(policy: serde_json::Value, stale_multiplier: f64) -> Dispatcher {
+        let config: AlertConfig = serde_json::from_value(json!({
+            "notifiers": [],
+            "realertInterval": 60,
+            "allowOutputAnnotation": false,
+            "watchdog": { "staleMultiplier": stale_multiplier },
+        }))
+        .unwrap();
+        let (_, updates) = unbounded_channel();
+        let (_, changes) = unbounded_channel();
+
+        Dispatcher::new(
+            config,
+            HashMap::from([(1, check_info(policy))]),
+            SkippedChecks::default(),
+            updates,
+            changes,
+        )
+        .unwrap()
+    }
+
+    /// Records the names of alerts it's given, taking `delay` over each
+    struct Recorder {
+        delay: Duration,
+        delivered: Arc<Mutex<Vec<String>>>,
+    }
+
+    #[async_trait]
+    impl Notifier for Recorder {
+        fn name(&self) -> String {
+            "recorder".to_owned()
+        }
+
+        async fn notify(&self, alert: &Alert) {
+            sleep(self.delay).await;
+            let status = alert.lookup("status").unwrap();
+            self.delivered
+                .lock()
+                .unwrap()
+                .push(format!("{} {}", alert.labels["alertname"], status));
+        }
+
+        async fn shutdown(&self) {
+            self.delivered.lock().unwrap().push("shutdown".to_owned());
+        }
+    }
+
+    fn update(status: CheckStatus) -> CheckUpdate {
+        CheckUpdate {
+            id: 1,
+            status,
+            msg: None,
+            duration: None,
+        }
+    }
+
+    async fn run(dispatcher: &mut Dispatcher, statuses: &str) {
+        for status in statuses.chars() {
+            let status = match status {
+                'F' => CheckStatus::Failed,
+                'W' => CheckStatus::Warning,
+                'S' => CheckStatus::Succeeded,
+                _ => panic!("unknown status '{}'", status),
+            };
+            dispatcher.process_update(update(status)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_thresholds() {
+        let mut dispatcher = dispatcher(json!({ "failuresBeforeAlert": 3, "successesBeforeResolve": 2 }), 3.0);
+
+        // A success before the alert fires starts the count again
+        run(&mut dispatcher, "FFSFF").await;
+        assert!(dispatcher.active_alerts.is_empty());
+
+        run(&mut dispatcher, "F").await;
+        assert_eq!(dispatcher.active_alerts[&1].labels["alertname"], "HealthCheckFailed");
+
+        // As does a failure before the alert resolves
+        run(&mut dispatcher, "SFS").await;
+        assert!(dispatcher.active_alerts.contains_key(&1));
+
+        run(&mut dispatcher, "S").await;
+        assert!(dispatcher.active_alerts.is_empty());
+        assert!(dispatcher.failures.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_warnings() {
+        let mut dispatcher = dispatcher(json!({ "failuresBeforeAlert": 2, "successesBeforeResolve": 2 }), 3.0);
+        let severity = |dispatcher: &Dispatcher| {
+            dispatcher
+                .active_alerts
+                .get(&1)
+                .map(|alert| alert.labels["severity"].clone())
+        };
+
+        // Warnings and failures each need their own streak to alert
+        run(&mut dispatcher, "FWFW").await;
+        assert_eq!(severity(&dispatcher), None);
+
+        run(&mut dispatcher, "W").await;
+        assert_eq!(severity(&dispatcher).as_deref(), Some("warning"));
+
+        // Failing enough escalates the warning alert
+        run(&mut dispatcher, "F").await;
+        assert_eq!(severity(&dispatcher).as_deref(), Some("warning"));
+        run(&mut dispatcher, "F").await;
+        assert_eq!(severity(&dispatcher).as_deref(), Some("critical"));
+
+        // While a warning doesn't count as having recovered
+        run(&mut dispatcher, "WWSWS").await;
+        assert_eq!(severity(&dispatcher).as_deref(), Some("critical"));
+        run(&mut dispatcher, "S").await;
+        assert_eq!(severity(&dispatcher), None);
+    }
+
+    #[tokio::test]
+    async fn test_flapping() {
+        let mut dispatcher = dispatcher(json!({ "flapDetection": { "window": 6, "threshold": 3 } }), 3.0);
+
+        run(&mut dispatcher, "FSFS").await;
+        assert!(dispatcher.active_alerts.is_empty());
+        assert!(dispatcher.flapping_alerts.is_empty());
+
+        // The fourth change replaces the firing alert with a flapping one
+        run(&mut dispatcher, "F").await;
+        assert!(dispatcher.active_alerts.is_empty());
+        let alert = &dispatcher.flapping_alerts[&1];
+        assert_eq!(alert.labels["alertname"], "HealthCheckFlapping");
+        assert_eq!(alert.labels["flapping"], "true");
+
+        // Which keeps firing, without the usual alert, until the window has few enough changes
+        run(&mut dispatcher, "SS").await;
+        assert!(dispatcher.flapping_alerts.contains_key(&1));
+        assert!(dispatcher.active_alerts.is_empty());
+
+        run(&mut dispatcher, "S").await;
+        assert!(dispatcher.flapping_alerts.is_empty());
+        assert!(dispatcher.active_alerts.is_empty());
+
+        // After settling down, failures alert as usual
+        run(&mut dispatcher, "F").await;
+        assert!(dispatcher.active_alerts.contains_key(&1));
+        assert!(dispatcher.flapping_alerts.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_stale_checks() {
+        // Allowed 20ms between updates, including the 1s timeout
+        let mut dispatcher = dispatcher(json!({ "checkInterval": 1, "recheckInterval": 1 }), 0.01);
+
+        dispatcher.find_stale_checks();
+        assert!(dispatcher.stale_alerts.is_empty());
+
+        sleep(Duration::from_millis(50)).await;
+        dispatcher.find_stale_checks();
+        let alert = &dispatcher.stale_alerts[&1];
+        assert_eq!(alert.labels["alertname"], "HealthCheckStale");
+        assert_eq!(alert.labels["instance"], "web-1");
+
+        // Any update shows the check is alive again
+        dispatcher.process_update(update(CheckStatus::Running)).await;
+        assert!(dispatcher.stale_alerts.is_empty());
+
+        // A check announcing a long wait, e.g. for its schedule, isn't stale until that has passed
+        let until = OffsetDateTime::now_utc() + Duration::from_secs(60);
+        let waiting = CheckStatus::Waiting(Duration::from_secs(60), until, "next check".to_owned());
+        dispatcher.process_update(update(waiting)).await;
+        sleep(Duration::from_millis(50)).await;
+        dispatcher.find_stale_checks();
+        assert!(dispatcher.stale_alerts.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_retrying_check_is_not_stale() {
+        // Allowed 20ms between updates, with a 1s timeout
+        let mut dispatcher = dispatcher(json!({ "checkInterval": 1, "recheckInterval": 1 }), 0.01);
+
+        // A long backoff before a retry, e.g. with a large maxDelay, isn't stale
+        let retrying = CheckStatus::Retrying(3, Duration::from_secs(60));
+        dispatcher.process_update(update(retrying)).await;
+        sleep(Duration::from_millis(50)).await;
+        dispatcher.find_stale_checks();
+        assert!(dispatcher.stale_alerts.is_empty());
+
+        // Nor is an attempt which takes nearly as long as the check's timeout
+        dispatcher.registry.get_mut(&1).unwrap().check_timeout = Duration::from_secs(60);
+        dispatcher.process_update(update(CheckStatus::Running)).await;
+        sleep(Duration::from_millis(50)).await;
+        dispatcher.find_stale_checks();
+        assert!(dispatcher.stale_alerts.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_queued_check_is_not_stale() {
+        let mut dispatcher = dispatcher(json!({ "checkInterval": 1, "recheckInterval": 1 }), 0.01);
+
+        // Waiting behind other checks for a concurrency limit pauses the staleness clock
+        dispatcher.process_update(update(CheckStatus::Queued)).await;
+        sleep(Duration::from_millis(50)).await;
+        dispatcher.find_stale_checks();
+        assert!(dispatcher.stale_alerts.is_empty());
+
+        // Which starts again once the check runs
+        dispatcher.process_update(update(CheckStatus::Running)).await;
+        sleep(Duration::from_millis(50)).await;
+        dispatcher.find_stale_checks();
+        assert_eq!(dispatcher.stale_alerts.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_removed_check_is_not_stale() {
+        let mut dispatcher = dispatcher(json!({ "checkInterval": 1, "recheckInterval": 1 }), 0.01);
+
+        sleep(Duration::from_millis(50)).await;
+        dispatcher.find_stale_checks();
+        assert_eq!(dispatcher.stale_alerts.len(), 1);
+
+        dispatcher.process_change(RegistryChange::Remove(1)).await;
+        assert!(dispatcher.stale_alerts.is_empty());
+        dispatcher.find_stale_checks();
+        assert!(dispatcher.stale_alerts.is_empty());
+    }
+
+    /// Takes `delay` to fetch silences, which never match anything
+    struct SlowSilences {
+        delay: Duration,
+    }
+
+    #[async_trait]
+    impl Notifier for SlowSilences {
+        fn name(&self) -> String {
+            "slow silences".to_owned()
+        }
+
+        async fn notify(&self, _alert: &Alert) {}
+
+        async fn sync_silences(&self) {
+            sleep(self.delay).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_slow_silences_do_not_block() {
+        let mut dispatcher = dispatcher(json!({}), 3.0);
+        dispatcher.notifiers.push(Queue::new(Box::new(SlowSilences {
+            delay: Duration::from_secs(5),
+        })));
+
+        // Firing alerts and registry changes use the silences from the last sync, rather than fetching them
+        let started = Instant::now();
+        run(&mut dispatcher, "F").await;
+        assert!(dispatcher.active_alerts.contains_key(&1));
+        dispatcher.process_change(RegistryChange::Remove(1)).await;
+        assert!(started.elapsed() < Duration::from_millis(100));
+    }
+
+    #[tokio::test]
+    async fn test_removed_check_resolves_alerts() {
+        let mut dispatcher = dispatcher(json!({}), 3.0);
+        let delivered = Arc::new(Mutex::new(Vec::new()));
+        dispatcher.notifiers.push(Queue::new(Box::new(Recorder {
+            delay: Duration::ZERO,
+            delivered: delivered.clone(),
+        })));
+
+        run(&mut dispatcher, "F").await;
+        dispatcher.process_change(RegistryChange::Remove(1)).await;
+        assert!(dispatcher.active_alerts.is_empty());
+
+        join_all(dispatcher.notifiers.into_iter().map(Queue::close)).await;
+        assert_eq!(
+            *delivered.lock().unwrap(),
+            vec!["HealthCheckFailed firing", "HealthCheckFailed resolved", "shutdown"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_slow_notifier_does_not_block() {
+        let mut dispatcher = dispatcher(json!({}), 3.0);
+        let delivered = Arc::new(Mutex::new(Vec::new()));
+        dispatcher.notifiers.push(Queue::new(Box::new(Recorder {
+            delay: Duration::from_millis(200),
+            delivered: delivered.clone(),
+        })));
+
+        let started = Instant::now();
+        dispatcher.process_update(update(CheckStatus::Failed)).await;
+        dispatcher.process_update(update(CheckStatus::Succeeded)).await;
+        assert!(started.elapsed() < Duration::from_millis(100));
+        assert!(delivered.lock().unwrap().is_empty());
+
+        // Closing the queues waits for everything queued, in order
+        join_all(dispatcher.notifiers.into_iter().map(Queue::close)).await;
+        assert_eq!(
+            *delivered.lock().unwrap(),
+            vec!["HealthCheckFailed firing", "HealthCheckFailed resolved", "shutdown"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_annotations_rendered_on_refresh() {
+        let mut dispatcher = dispatcher(json!({}), 3.0);
+
+        dispatcher.process_update(update(CheckStatus::Failed)).await;
+        assert_eq!(dispatcher.active_alerts[&1].annotations["summary"], "1 failed runs");
+        let starts_at = dispatcher.active_alerts[&1].starts_at;
+
+        dispatcher.process_update(update(CheckStatus::Failed)).await;
+        dispatcher.process_update(update(CheckStatus::Failed)).await;
+        dispatcher.refresh();
+        let alert = &dispatcher.active_alerts[&1];
+        assert_eq!(alert.annotations["summary"], "3 failed runs");
+        assert_eq!(alert.starts_at, starts_at);
+    }
+}
@@ -0,0 +1,420 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use log::{info, warn};
+use serde_json::json;
+use simple_eyre::eyre::{eyre, Result, WrapErr};
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::task::{spawn_local, JoinHandle};
+
+use crate::notify::RegistryChange;
+use crate::{
+    alert, config, limit, maintenance, notify, sanitize, select, source, CheckInfo, CheckUpdate, RunnableCheck,
+    UpdateChan,
+};
+
+pub struct LoadedCheck {
+    /// Stays the same across reloads as long as the check's type, parameters and labels do
+    pub identity: String,
+    /// The check's definition, together with the global settings which affect it
+    pub definition: serde_json::Value,
+    pub runnable: RunnableCheck,
+    pub info: CheckInfo,
+}
+
+pub struct Loaded {
+    pub alerting: Option<alert::Config>,
+    /// The raw alerting settings, to notice changes which can't be applied without a restart
+    pub alerting_definition: serde_json::Value,
+    pub checks: Vec<LoadedCheck>,
+}
+
+/// Loads checks from the config files, giving each a stable ID
+pub struct CheckSet {
+    source: source::Source,
+    selector: Option<select::Term>,
+    updates: UnboundedSender<CheckUpdate>,
+    ids: HashMap<String, usize>,
+    next_id: usize,
+    limiter: limit::Limiter,
+    maintenance: maintenance::Maintenance,
+}
+
+impl CheckSet {
+    pub fn new(source: source::Source, selector: Option<select::Term>, updates: UnboundedSender<CheckUpdate>) -> Self {
+        CheckSet {
+            source,
+            selector,
+            updates,
+            ids: HashMap::new(),
+            next_id: 0,
+            limiter: limit::Limiter::default(),
+            maintenance: maintenance::Maintenance::default(),
+        }
+    }
+
+    /// The maintenance windows shared by every check, which can be added to at runtime
+    pub fn maintenance(&self) -> maintenance::Maintenance {
+        self.maintenance.clone()
+    }
+
+    /// Whether reloading can pick up changes, which it can't when the config came from stdin
+    pub fn reloadable(&self) -> bool {
+        self.source.rereadable()
+    }
+
+    fn id_for(&mut self, identity: &str) -> usize {
+        if let Some(id) = self.ids.get(identity) {
+            return *id;
+        }
+
+        let id = self.next_id;
+        self.next_id += 1;
+        self.ids.insert(identity.to_owned(), id);
+        id
+    }
+
+    pub fn load(&mut self) -> Result<Loaded> {
+        let merged = self.source.read()?;
+        let raw = &merged.raw;
+        let config: config::Config = serde_path_to_error::deserialize(raw.clone()).wrap_err("Invalid config")?;
+
+        let windows = maintenance::Maintenance::parse(config.maintenance)?;
+        let sanitizer = Arc::new(sanitize::Sanitizer::new(config.output)?);
+        self.limiter.configure(config.concurrency);
+
+        // The number of times each check has been seen so far, and the index it was first seen at
+        let mut occurrences: HashMap<String, (usize, usize)> = HashMap::new();
+        let mut checks = Vec::new();
+
+        for (index, check_def) in config.checks.into_iter().enumerate() {
+            if let Some(ref sel) = self.selector {
+                if !sel.matches(&check_def.labels) {
+                    continue;
+                }
+            }
+
+            let params = check_def.config.params()?;
+            let identity = check_def.identity()?;
+
+            // Identical checks within a file are told apart by the order they appear in
+            let (occurrence, first_index) = occurrences.entry(identity.clone()).or_insert((0, index));
+            let first_index = *first_index;
+            let identity = format!("{}#{}", identity, occurrence);
+            *occurrence += 1;
+
+            let id = self.id_for(&identity);
+            let checker = check_def.config.clone().into_check(id)?;
+
+            if !merged.same_file(index, first_index) {
+                return Err(eyre!(
+                    "Check '{}' is defined in both {} and {}, with the same labels",
+                    checker.name(),
+                    merged.check_path(first_index),
+                    merged.check_path(index)
+                ));
+            }
+
+            let check_windows = self.maintenance.for_labels(&check_def.labels);
+
+            // Limits can group checks by the automatic alert labels too, which the check's own labels override
+            let mut limit_labels = HashMap::from([
+                ("check_type".to_owned(), check_def.config.check_type().to_owned()),
+                ("instance".to_owned(), checker.instance()),
+            ]);
+            limit_labels.extend(check_def.labels.clone());
+            let limits = self.limiter.for_labels(&limit_labels);
+
+            let info = CheckInfo {
+                name: checker.name(),
+                check_type: check_def.config.check_type().to_owned(),
+                instance: checker.instance(),
+                params,
+                alert_policy: check_def.alert_policy.clone(),
+                check_timeout: check_def.check_timeout,
+                maintenance: check_windows.clone(),
+                labels: check_def.labels.clone(),
+                annotations: check_def.annotations.clone(),
+            };
+
+            let runnable = RunnableCheck {
+                alert_policy: check_def.alert_policy,
+                spread_key: alert::spread_key(&identity),
+                maintenance: check_windows,
+                checker,
+                retry_policy: check_def.retry_policy,
+                timeout: check_def.check_timeout,
+                warn_after: check_def.warn_after,
+                fail_after: check_def.fail_after,
+                limits,
+                updates: UpdateChan::new(id, sanitizer.clone(), self.updates.clone()),
+            };
+
+            checks.push(LoadedCheck {
+                identity,
+                definition: json!({
+                    "check": raw["checks"][index],
+                    "output": raw["output"],
+                    "concurrency": raw["concurrency"],
+                }),
+                runnable,
+                info,
+            });
+        }
+
+        self.maintenance.configure(windows);
+
+        Ok(Loaded {
+            alerting: config.alerting,
+            alerting_definition: raw["alerting"].clone(),
+            checks,
+        })
+    }
+}
+
+struct RunningCheck {
+    id: usize,
+    name: String,
+    definition: serde_json::Value,
+    handle: JoinHandle<()>,
+}
+
+/// The check loops currently running in alerting mode, which must be driven from within a `LocalSet`
+pub struct Running {
+    alerting_definition: serde_json::Value,
+    changes: UnboundedSender<RegistryChange>,
+    checks: HashMap<String, RunningCheck>,
+    skipped: notify::SkippedChecks,
+}
+
+impl Running {
+    /// Starts every loaded check, which the dispatcher's registry must already include
+    pub fn new(loaded: Loaded, changes: UnboundedSender<RegistryChange>, skipped: notify::SkippedChecks) -> Self {
+        let mut running = Running {
+            alerting_definition: loaded.alerting_definition,
+            changes,
+            checks: HashMap::new(),
+            skipped,
+        };
+
+        for check in loaded.checks {
+            running.start(check);
+        }
+
+        running
+    }
+
+    fn start(&mut self, check: LoadedCheck) {
+        let id = check.runnable.checker.id();
+        let handle = spawn_local(alert::run_check_for_alerts(check.runnable, self.skipped.clone()));
+
+        self.checks.insert(
+            check.identity,
+            RunningCheck {
+                id,
+                name: check.info.name,
+                definition: check.definition,
+                handle,
+            },
+        );
+    }
+
+    fn send(&self, change: RegistryChange) {
+        if self.changes.send(change).is_err() {
+            warn!("Unable to update the check registry, the alert dispatcher has stopped");
+        }
+    }
+
+    /// Starts checks which weren't running, restarts changed checks and stops removed checks,
+    /// leaving unchanged checks and their alerts alone
+    pub fn apply(&mut self, loaded: Loaded) {
+        if loaded.alerting_definition != self.alerting_definition {
+            warn!("Changes to alerting settings only take effect after a restart");
+        }
+
+        let mut previous = std::mem::take(&mut self.checks);
+
+        for check in loaded.checks {
+            match previous.remove(&check.identity) {
+                Some(running) if running.definition == check.definition => {
+                    self.checks.insert(check.identity, running);
+                }
+                Some(running) => {
+                    info!("Restarting changed check - {}", check.info.name);
+                    running.handle.abort();
+                    self.send(RegistryChange::Remove(running.id));
+                    self.send(RegistryChange::Add(running.id, Box::new(check.info.clone())));
+                    self.start(check);
+                }
+                None => {
+                    info!("Starting check - {}", check.info.name);
+                    self.send(RegistryChange::Add(
+                        check.runnable.checker.id(),
+                        Box::new(check.info.clone()),
+                    ));
+                    self.start(check);
+                }
+            }
+        }
+
+        for running in previous.into_values() {
+            info!("Stopping removed check - {}", running.name);
+            running.handle.abort();
+            self.send(RegistryChange::Remove(running.id));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    use serde_json::Value;
+    use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+    use tokio::task::LocalSet;
+
+    use super::*;
+
+    /// A directory of config files for a single test, which starts out empty
+    fn config_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("colmena-health-reload-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// An http check which won't run for a long time, so it never makes a request
+    fn check(url: &str, timeout: u64) -> Value {
+        json!({
+            "type": "http",
+            "params": { "url": url },
+            "checkTimeout": timeout,
+            "alertPolicy": { "schedule": "0 0 1 1 *" },
+        })
+    }
+
+    fn write(dir: &Path, file: &str, checks: Vec<Value>) {
+        fs::write(dir.join(file), json!({ "checks": checks }).to_string()).unwrap();
+    }
+
+    fn check_set(dir: &Path) -> (CheckSet, UnboundedReceiver<CheckUpdate>) {
+        let (tx, rx) = unbounded_channel();
+        let source = source::Source::new(vec![dir.to_string_lossy().into_owned()], None);
+        (CheckSet::new(source, None, tx), rx)
+    }
+
+    fn drain(changes: &mut UnboundedReceiver<RegistryChange>) -> Vec<String> {
+        let mut drained = Vec::new();
+        while let Ok(change) = changes.try_recv() {
+            drained.push(match change {
+                RegistryChange::Add(id, info) => format!("add {} {}", id, info.name),
+                RegistryChange::Remove(id) => format!("remove {}", id),
+            });
+        }
+        drained
+    }
+
+    /// The IDs of the checks whose tasks are still running, by name
+    fn running_ids(running: &Running) -> HashMap<String, usize> {
+        running
+            .checks
+            .values()
+            .filter(|check| !check.handle.is_finished())
+            .map(|check| (check.name.clone(), check.id))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_reload() {
+        let dir = config_dir("apply");
+        write(
+            &dir,
+            "a.json",
+            vec![
+                check("http://web-1/", 10),
+                check("http://web-2/", 10),
+                check("http://web-3/", 10),
+            ],
+        );
+        let (mut check_set, _updates) = check_set(&dir);
+        let (changes_tx, mut changes) = unbounded_channel();
+
+        LocalSet::new()
+            .run_until(async move {
+                let mut running = Running::new(check_set.load().unwrap(), changes_tx, Default::default());
+                let before = running_ids(&running);
+                assert_eq!(before.len(), 3);
+
+                // web-1 is unchanged, web-2's timeout changed, web-3 is removed and web-4 is new
+                write(
+                    &dir,
+                    "a.json",
+                    vec![
+                        check("http://web-1/", 10),
+                        check("http://web-2/", 20),
+                        check("http://web-4/", 10),
+                    ],
+                );
+                running.apply(check_set.load().unwrap());
+                tokio::task::yield_now().await;
+
+                let after = running_ids(&running);
+                assert_eq!(after.len(), 3);
+                assert_eq!(after["http http://web-1/"], before["http http://web-1/"]);
+                assert_eq!(after["http http://web-2/"], before["http http://web-2/"]);
+                assert!(!after.contains_key("http http://web-3/"));
+                assert_eq!(
+                    drain(&mut changes),
+                    vec![
+                        format!("remove {}", before["http http://web-2/"]),
+                        format!("add {} http http://web-2/", before["http http://web-2/"]),
+                        format!("add {} http http://web-4/", after["http http://web-4/"]),
+                        format!("remove {}", before["http http://web-3/"]),
+                    ]
+                );
+
+                // Reloading the same config again leaves everything alone
+                running.apply(check_set.load().unwrap());
+                assert_eq!(running_ids(&running), after);
+                assert!(drain(&mut changes).is_empty());
+
+                // A config which doesn't load leaves the running checks as they were
+                fs::write(dir.join("a.json"), "{ \"checks\": [").unwrap();
+                assert!(check_set.load().is_err());
+                assert_eq!(running_ids(&running), after);
+
+                // And once fixed, the checks pick up their previous IDs
+                write(
+                    &dir,
+                    "a.json",
+                    vec![check("http://web-1/", 10), check("http://web-3/", 10)],
+                );
+                let loaded = check_set.load().unwrap();
+                let ids: Vec<usize> = loaded.checks.iter().map(|check| check.runnable.checker.id()).collect();
+                assert_eq!(ids, vec![before["http http://web-1/"], before["http http://web-3/"]]);
+
+                fs::remove_dir_all(&dir).unwrap();
+            })
+            .await;
+    }
+
+    #[test]
+    fn test_duplicates_across_files() {
+        let dir = config_dir("duplicates");
+        write(&dir, "a.json", vec![check("http://web-1/", 10)]);
+        write(&dir, "b.json", vec![check("http://web-1/", 20)]);
+        let (mut check_set, _updates) = check_set(&dir);
+
+        let err = format!("{:#}", check_set.load().err().unwrap());
+        assert!(err.contains("is defined in both"), "{}", err);
+        assert!(
+            err.contains("a.json:checks[0]") && err.contains("b.json:checks[0]"),
+            "{}",
+            err
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
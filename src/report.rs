@@ -1,4 +1,4 @@
-use std::{collections::HashMap, future};
+use std::collections::HashMap;
 
 use futures::stream::FuturesUnordered;
 use futures::StreamExt;
@@ -13,7 +13,10 @@ async fn print_verbose(registry: HashMap<usize, CheckInfo>, mut rx: UnboundedRec
     while let Some(update) = rx.recv().await {
         let name = registry.get(&update.id).map(|info| &info.name).unwrap_or(&unknown);
 
-        println!("{}: {}", name, update.status);
+        match update.duration {
+            Some(duration) => println!("{} (took {:?}): {}", name, duration, update.status),
+            None => println!("{}: {}", name, update.status),
+        }
 
         if let Some(msg) = update.msg {
             for line in msg.lines() {
@@ -27,6 +30,7 @@ pub fn run_report(
     checks: Vec<RunnableCheck>,
     registry: HashMap<usize, CheckInfo>,
     rx: UnboundedReceiver<CheckUpdate>,
+    fail_on_warning: bool,
 ) -> Result<()> {
     let checks: FuturesUnordered<_> = checks.into_iter().map(run_check).collect();
 
@@ -38,12 +42,18 @@ pub fn run_report(
 
     let printer = rt.spawn(print_verbose(registry, rx));
 
-    let failures = rt.block_on(checks.filter(|res| future::ready(res.is_failure())).count());
+    let results: Vec<_> = rt.block_on(checks.collect());
+    let failures = results.iter().filter(|res| res.is_failure()).count();
+    let warnings = results.iter().filter(|res| res.is_warning()).count();
 
     rt.block_on(printer)?;
 
-    if failures > 0 {
-        return Err(eyre!("{} check(s) failed", failures));
+    if failures > 0 || (fail_on_warning && warnings > 0) {
+        return Err(eyre!("{} check(s) failed, {} check(s) warned", failures, warnings));
+    }
+
+    if warnings > 0 {
+        println!("{} check(s) warned", warnings);
     }
 
     Ok(())
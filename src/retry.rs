@@ -1,23 +1,139 @@
+use std::fmt::{self, Display};
 use std::time::Duration;
 
-use serde::Deserialize;
+use rand::Rng;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
 use serde_with::{serde_as, DurationSeconds};
-use tokio::time::sleep;
+use tokio::time::{sleep, Instant};
+
+fn default_max_retries() -> u16 {
+    3
+}
+
+fn default_initial() -> Duration {
+    Duration::from_secs(1)
+}
+
+fn default_multiplier() -> f64 {
+    1.1
+}
+
+/// How the wait before each retry is randomized, so checks failing together don't retry together
+#[derive(Clone, Copy, Default, Deserialize, JsonSchema, Serialize, Debug, PartialEq)]
+#[schemars(rename = "RetryJitter")]
+#[serde(rename_all = "lowercase")]
+pub enum Jitter {
+    /// Always wait exactly the backoff delay
+    #[default]
+    None,
+    /// Wait anywhere between zero and the backoff delay
+    Full,
+    /// Wait at least half the backoff delay, and up to all of it
+    Equal,
+}
+
+impl Jitter {
+    /// Applies the jitter to a delay, given a random fraction in `[0, 1]`
+    fn apply(&self, delay: Duration, fraction: f64) -> Duration {
+        match self {
+            Jitter::None => delay,
+            Jitter::Full => delay.mul_f64(fraction),
+            Jitter::Equal => delay / 2 + (delay / 2).mul_f64(fraction),
+        }
+    }
+}
 
 #[serde_as]
-#[derive(Clone, Deserialize, Debug)]
+#[derive(Clone, Deserialize, JsonSchema, Serialize, Debug)]
+#[schemars(rename = "RetryPolicy")]
 #[serde(rename_all = "camelCase")]
 pub struct Policy {
+    /// The number of retries before failing the check, or 0 to disable retries
+    #[serde(default = "default_max_retries")]
     max_retries: u16,
+    /// The number of seconds to wait before the first retry
     #[serde_as(as = "DurationSeconds<f64>")]
+    #[schemars(with = "f64")]
+    #[serde(default = "default_initial")]
     initial: Duration,
+    /// Applied to the wait before each further retry, for exponential backoff
+    #[serde(default = "default_multiplier")]
     multiplier: f64,
+    /// The longest number of seconds to wait before any retry, however far the backoff has grown
+    #[serde_as(as = "Option<DurationSeconds<f64>>")]
+    #[schemars(with = "Option<f64>")]
+    #[serde(default)]
+    max_delay: Option<Duration>,
+    /// How each wait before a retry is randomized: `none`, `full` or `equal`
+    #[serde(default)]
+    jitter: Jitter,
+    /// The number of seconds after the first attempt beyond which no retry starts, whatever `maxRetries` allows
+    #[serde_as(as = "Option<DurationSeconds<f64>>")]
+    #[schemars(with = "Option<f64>")]
+    #[serde(default)]
+    deadline: Option<Duration>,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Policy {
+            max_retries: default_max_retries(),
+            initial: default_initial(),
+            multiplier: default_multiplier(),
+            max_delay: None,
+            jitter: Jitter::None,
+            deadline: None,
+        }
+    }
+}
+
+impl Display for Policy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} retries, after {:?} x{}",
+            self.max_retries, self.initial, self.multiplier
+        )?;
+
+        if let Some(max_delay) = self.max_delay {
+            write!(f, " up to {:?}", max_delay)?;
+        }
+        match self.jitter {
+            Jitter::None => {}
+            Jitter::Full => write!(f, ", full jitter")?,
+            Jitter::Equal => write!(f, ", equal jitter")?,
+        }
+        if let Some(deadline) = self.deadline {
+            write!(f, ", within {:?}", deadline)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Why a failing check stopped being retried
+#[derive(Debug, PartialEq)]
+pub enum GiveUp {
+    MaxRetries,
+    Deadline(Duration),
+}
+
+impl Display for GiveUp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GiveUp::MaxRetries => write!(f, "Maximum retries reached"),
+            GiveUp::Deadline(deadline) => write!(f, "Retry deadline of {:?} reached", deadline),
+        }
+    }
 }
 
 pub struct Retrier {
     policy: Policy,
+    /// The last backoff delay, before jitter, which the next one grows from
     last: Option<Duration>,
     attempts: u16,
+    started: Instant,
 }
 
 impl Retrier {
@@ -26,24 +142,105 @@ impl Retrier {
             policy,
             last: None,
             attempts: 0,
+            started: Instant::now(),
         }
     }
 
+    /// Decides on the next retry, returning its attempt number and how long to wait before it
+    pub fn next_retry(&mut self) -> Result<(u16, Duration), GiveUp> {
+        self.next_retry_with(rand::thread_rng().gen_range(0.0..=1.0))
+    }
+
+    /// Waits before the next retry, returning its attempt number, or `None` once retries are used up
     pub async fn retry(&mut self) -> Option<u16> {
+        let (attempt, delay) = self.next_retry().ok()?;
+        sleep(delay).await;
+
+        Some(attempt)
+    }
+
+    fn next_retry_with(&mut self, fraction: f64) -> Result<(u16, Duration), GiveUp> {
         if self.attempts >= self.policy.max_retries {
-            return None;
+            return Err(GiveUp::MaxRetries);
         }
 
-        let dur = match self.last {
+        let mut backoff = match self.last {
             None => self.policy.initial,
             Some(last_dur) => last_dur.mul_f64(self.policy.multiplier),
         };
+        if let Some(max_delay) = self.policy.max_delay {
+            backoff = backoff.min(max_delay);
+        }
+        let delay = self.policy.jitter.apply(backoff, fraction);
 
-        sleep(dur).await;
+        if let Some(deadline) = self.policy.deadline {
+            if self.started.elapsed() + delay >= deadline {
+                return Err(GiveUp::Deadline(deadline));
+            }
+        }
 
-        self.last = Some(dur);
+        self.last = Some(backoff);
         self.attempts += 1;
 
-        Some(self.attempts)
+        Ok((self.attempts, delay))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(json: serde_json::Value) -> Policy {
+        serde_json::from_value(json).unwrap()
+    }
+
+    #[test]
+    fn test_backoff() {
+        let mut retrier = Retrier::new(policy(serde_json::json!({
+            "maxRetries": 4,
+            "initial": 1,
+            "multiplier": 4,
+            "maxDelay": 10,
+            "jitter": "equal",
+        })));
+
+        let delays: Vec<_> = (0..5).map(|_| retrier.next_retry_with(0.0)).collect();
+        assert_eq!(
+            delays,
+            vec![
+                Ok((1, Duration::from_millis(500))),
+                Ok((2, Duration::from_secs(2))),
+                Ok((3, Duration::from_secs(5))),
+                Ok((4, Duration::from_secs(5))),
+                Err(GiveUp::MaxRetries),
+            ]
+        );
+
+        assert_eq!(
+            Jitter::Full.apply(Duration::from_secs(10), 0.25),
+            Duration::from_millis(2500)
+        );
+        assert_eq!(
+            Jitter::Equal.apply(Duration::from_secs(10), 1.0),
+            Duration::from_secs(10)
+        );
+    }
+
+    #[test]
+    fn test_deadline() {
+        let mut retrier = Retrier::new(policy(serde_json::json!({
+            "maxRetries": 10,
+            "initial": 2,
+            "multiplier": 2,
+            "deadline": 5,
+        })));
+
+        assert_eq!(retrier.next_retry_with(0.0), Ok((1, Duration::from_secs(2))));
+        // The deadline is measured from the first attempt, so time spent waiting and running counts too
+        retrier.started -= Duration::from_secs(2);
+        assert_eq!(
+            retrier.next_retry_with(0.0),
+            Err(GiveUp::Deadline(Duration::from_secs(5)))
+        );
     }
 }
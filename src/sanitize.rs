@@ -0,0 +1,182 @@
+use regex::Regex;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use simple_eyre::eyre::{Result, WrapErr};
+
+#[derive(Clone, Copy, Default, Deserialize, JsonSchema, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Keep {
+    Head,
+    Tail,
+    #[default]
+    Both,
+}
+
+fn default_max_length() -> Option<usize> {
+    Some(4096)
+}
+
+fn default_strip_control() -> bool {
+    true
+}
+
+/// Controls how check output is cleaned up before it's reported or attached to alerts
+#[derive(Clone, Deserialize, JsonSchema, Debug)]
+#[schemars(rename = "OutputConfig")]
+#[serde(rename_all = "camelCase")]
+pub struct Config {
+    /// The maximum length of output in bytes, or null for no limit
+    #[serde(default = "default_max_length")]
+    max_length: Option<usize>,
+    /// Which part of over-long output to keep
+    #[serde(default)]
+    keep: Keep,
+    /// Strip ANSI escape sequences and control characters other than newlines and tabs
+    #[serde(default = "default_strip_control")]
+    strip_control: bool,
+    /// Regexes whose matches are replaced with `[REDACTED]`
+    #[serde(default)]
+    redact: Vec<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            max_length: default_max_length(),
+            keep: Keep::default(),
+            strip_control: default_strip_control(),
+            redact: Vec::new(),
+        }
+    }
+}
+
+pub struct Sanitizer {
+    ansi: Regex,
+    config: Config,
+    redact: Vec<Regex>,
+}
+
+impl Sanitizer {
+    pub fn new(config: Config) -> Result<Self> {
+        let redact = config
+            .redact
+            .iter()
+            .map(|pattern| Regex::new(pattern).wrap_err(format!("Invalid redaction regex '{}'", pattern)))
+            .collect::<Result<_>>()?;
+
+        Ok(Sanitizer {
+            ansi: Regex::new(r"\x1b(\[[0-?]*[ -/]*[@-~]|\][^\x07\x1b]*(\x07|\x1b\\)|[@-Z\\-_])")
+                .expect("ANSI escape regex is valid"),
+            config,
+            redact,
+        })
+    }
+
+    pub fn sanitize(&self, output: &str) -> String {
+        let mut output = output.to_owned();
+
+        if self.config.strip_control {
+            output = self.ansi.replace_all(&output, "").into_owned();
+            output.retain(|c| !c.is_control() || c == '\n' || c == '\t');
+        }
+
+        for regex in self.redact.iter() {
+            output = regex.replace_all(&output, "[REDACTED]").into_owned();
+        }
+
+        match self.config.max_length {
+            Some(max_length) if output.len() > max_length => truncate(&output, max_length, self.config.keep),
+            _ => output,
+        }
+    }
+}
+
+fn floor_char_boundary(s: &str, mut index: usize) -> usize {
+    while !s.is_char_boundary(index) {
+        index -= 1;
+    }
+    index
+}
+
+fn ceil_char_boundary(s: &str, mut index: usize) -> usize {
+    while !s.is_char_boundary(index) {
+        index += 1;
+    }
+    index
+}
+
+fn truncate(output: &str, max_length: usize, keep: Keep) -> String {
+    let marker = |removed: usize| format!("[... {} bytes truncated ...]", removed);
+
+    match keep {
+        Keep::Head => {
+            let end = floor_char_boundary(output, max_length);
+            format!("{}\n{}", &output[..end], marker(output.len() - end))
+        }
+        Keep::Tail => {
+            let start = ceil_char_boundary(output, output.len() - max_length);
+            format!("{}\n{}", marker(start), &output[start..])
+        }
+        Keep::Both => {
+            let end = floor_char_boundary(output, max_length / 2);
+            let start = ceil_char_boundary(output, output.len() - (max_length - max_length / 2));
+            format!("{}\n{}\n{}", &output[..end], marker(start - end), &output[start..])
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sanitizer(max_length: Option<usize>, keep: Keep, redact: &[&str]) -> Sanitizer {
+        Sanitizer::new(Config {
+            max_length,
+            keep,
+            strip_control: true,
+            redact: redact.iter().map(|r| r.to_string()).collect(),
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_strip_control() {
+        let s = sanitizer(None, Keep::Both, &[]);
+
+        assert_eq!(s.sanitize("\x1b[31mred\x1b[0m\ttext\r\n\x07"), "red\ttext\n");
+    }
+
+    #[test]
+    fn test_redact() {
+        let s = sanitizer(None, Keep::Both, &[r"token=\w+", "hunter2"]);
+
+        assert_eq!(
+            s.sanitize("url?token=abc123&user=me password hunter2"),
+            "url?[REDACTED]&user=me password [REDACTED]"
+        );
+    }
+
+    #[test]
+    fn test_truncate() {
+        let output = "0123456789";
+
+        assert_eq!(sanitizer(Some(20), Keep::Both, &[]).sanitize(output), output);
+        assert_eq!(
+            sanitizer(Some(4), Keep::Head, &[]).sanitize(output),
+            "0123\n[... 6 bytes truncated ...]"
+        );
+        assert_eq!(
+            sanitizer(Some(4), Keep::Tail, &[]).sanitize(output),
+            "[... 6 bytes truncated ...]\n6789"
+        );
+        assert_eq!(
+            sanitizer(Some(4), Keep::Both, &[]).sanitize(output),
+            "01\n[... 6 bytes truncated ...]\n89"
+        );
+        // never splits a multi-byte character
+        assert_eq!(
+            sanitizer(Some(2), Keep::Head, &[]).sanitize("ééé"),
+            "é\n[... 4 bytes truncated ...]"
+        );
+    }
+}
@@ -78,7 +78,7 @@ impl FromStr for Term {
     }
 }
 
-trait TermMatcher {
+trait TermMatcher: Send + Sync {
     fn matches(&self, label_value: &str) -> bool;
 }
 
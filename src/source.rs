@@ -0,0 +1,182 @@
+use std::fs;
+use std::io::{stdin, Read};
+use std::path::Path;
+
+use serde_json::Value;
+use simple_eyre::eyre::{eyre, Result, WrapErr};
+
+use crate::config::Format;
+use crate::interpolate;
+
+/// Merges `from` into `into`: objects are merged key by key, lists are appended to, and anything else is replaced
+pub fn merge(into: &mut Value, from: Value) {
+    match (into, from) {
+        (Value::Object(into), Value::Object(from)) => {
+            for (key, value) in from {
+                match into.get_mut(&key) {
+                    Some(existing) => merge(existing, value),
+                    None => {
+                        into.insert(key, value);
+                    }
+                }
+            }
+        }
+        (Value::Array(into), Value::Array(from)) => into.extend(from),
+        (into, from) => *into = from,
+    }
+}
+
+/// A config merged from one or more files
+pub struct Merged {
+    pub raw: Value,
+    /// For each merged check, the file it came from and its index within that file
+    pub check_origins: Vec<(String, usize)>,
+}
+
+impl Merged {
+    /// Whether two merged checks came from the same file
+    pub fn same_file(&self, index: usize, other: usize) -> bool {
+        self.check_origins.get(index).map(|origin| &origin.0) == self.check_origins.get(other).map(|origin| &origin.0)
+    }
+
+    /// Describes where a merged check came from, naming its file when there's more than one
+    pub fn check_path(&self, index: usize) -> String {
+        let single_file = self.check_origins.windows(2).all(|pair| pair[0].0 == pair[1].0);
+
+        match self.check_origins.get(index) {
+            Some((file, local_index)) if !single_file => format!("{}:checks[{}]", file, local_index),
+            _ => format!("checks[{}]", index),
+        }
+    }
+}
+
+/// The files and directories a config is read from, in order
+pub struct Source {
+    paths: Vec<String>,
+    format: Option<Format>,
+    /// The config read from stdin, which can only be read once
+    stdin_data: Option<String>,
+}
+
+impl Source {
+    /// Reads configs in `format` if given, or else as guessed from each file's extension
+    pub fn new(paths: Vec<String>, format: Option<Format>) -> Self {
+        Source {
+            paths,
+            format,
+            stdin_data: None,
+        }
+    }
+
+    /// Whether reading again can pick up changes, which it can't for a config read from stdin
+    pub fn rereadable(&self) -> bool {
+        !self.paths.iter().any(|path| path == "-")
+    }
+
+    /// Lists the files to read, expanding directories into the config files directly within them, by name
+    fn files(&self) -> Result<Vec<String>> {
+        let mut files = Vec::new();
+
+        for path in self.paths.iter() {
+            if path == "-" || !Path::new(path).is_dir() {
+                files.push(path.clone());
+                continue;
+            }
+
+            let mut entries: Vec<String> = fs::read_dir(path)
+                .wrap_err(format!("Unable to read config directory '{}'", path))?
+                .map(|entry| Ok(entry?.path()))
+                .collect::<Result<Vec<_>>>()?
+                .into_iter()
+                .filter(|entry| entry.is_file())
+                .filter(|entry| {
+                    let extension = entry.extension().and_then(|ext| ext.to_str());
+                    matches!(extension, Some("json" | "yaml" | "yml" | "toml"))
+                })
+                .map(|entry| entry.to_string_lossy().into_owned())
+                .collect();
+            entries.sort();
+
+            files.extend(entries);
+        }
+
+        Ok(files)
+    }
+
+    fn read_file(&mut self, file: &str) -> Result<String> {
+        if file != "-" {
+            return fs::read_to_string(file).wrap_err(format!("Unable to read config file '{}'", file));
+        }
+
+        if self.stdin_data.is_none() {
+            let mut buf = String::new();
+            stdin().read_to_string(&mut buf)?;
+            self.stdin_data = Some(buf);
+        }
+
+        Ok(self.stdin_data.clone().unwrap_or_default())
+    }
+
+    pub fn read(&mut self) -> Result<Merged> {
+        let files = self.files()?;
+        if files.is_empty() {
+            return Err(eyre!("No config files found in {}", self.paths.join(", ")));
+        }
+
+        let mut raw = Value::Object(Default::default());
+        let mut check_origins = Vec::new();
+
+        for file in files {
+            let data = self.read_file(&file)?;
+            let format = self.format.unwrap_or_else(|| Format::from_path(&file));
+            let mut value = format
+                .parse(&data)
+                .wrap_err(format!("Unable to parse config file '{}'", file))?;
+            interpolate::interpolate(&mut value, "")
+                .wrap_err(format!("Unable to substitute values into config file '{}'", file))?;
+
+            if let Some(checks) = value.get("checks").and_then(Value::as_array) {
+                check_origins.extend((0..checks.len()).map(|index| (file.clone(), index)));
+            }
+
+            merge(&mut raw, value);
+        }
+
+        Ok(Merged { raw, check_origins })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_merge() {
+        let mut config = json!({
+            "checks": [{ "type": "dns" }],
+            "alerting": { "realertInterval": 60, "notifiers": [{ "type": "webhook" }] },
+        });
+
+        merge(
+            &mut config,
+            json!({
+                "checks": [{ "type": "http" }],
+                "alerting": { "realertInterval": 30, "watchdog": {}, "notifiers": [{ "type": "email" }] },
+            }),
+        );
+
+        assert_eq!(
+            config,
+            json!({
+                "checks": [{ "type": "dns" }, { "type": "http" }],
+                "alerting": {
+                    "realertInterval": 30,
+                    "watchdog": {},
+                    "notifiers": [{ "type": "webhook" }, { "type": "email" }],
+                },
+            })
+        );
+    }
+}
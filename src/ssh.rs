@@ -1,15 +1,24 @@
 use async_process::{Command, Stdio};
 use async_trait::async_trait;
-use serde::Deserialize;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
 use simple_eyre::eyre::{eyre, Result, WrapErr};
 
-use crate::{CheckStatus, Checker as CheckerTrait, UpdateChan};
+use crate::{CheckStatus, Checker as CheckerTrait, Health, UpdateChan};
 
-#[derive(Clone, Deserialize, Debug)]
+#[derive(Clone, Deserialize, JsonSchema, Serialize, Debug)]
+#[schemars(rename = "SshConfig")]
+#[serde(rename_all = "camelCase")]
 pub struct Config {
+    /// The command to run on the target, which passes if it exits with 0
     command: String,
+    /// The hostname of the target
     hostname: String,
+    /// The username to connect as, or whatever ssh picks from its config if not given
     username: Option<String>,
+    /// Exit codes which indicate a warning rather than a failure
+    #[serde(default)]
+    warning_exit_codes: Vec<i32>,
 }
 
 pub struct Checker {
@@ -33,7 +42,11 @@ impl CheckerTrait for Checker {
         format!("ssh {}: '{}'", self.config.hostname, self.config.command)
     }
 
-    async fn check(&self, updates: &UpdateChan) -> Result<()> {
+    fn instance(&self) -> String {
+        self.config.hostname.clone()
+    }
+
+    async fn check(&self, updates: &UpdateChan) -> Result<Health> {
         let mut ssh = Command::new("ssh");
         ssh.kill_on_drop(true)
             .stdin(Stdio::null())
@@ -64,6 +77,15 @@ impl CheckerTrait for Checker {
         }
 
         if !output.status.success() {
+            if let Some(exit_code) = output.status.code() {
+                if self.config.warning_exit_codes.contains(&exit_code) {
+                    return Ok(Health::Warning(format!(
+                        "Command returned exit code {}\n{}",
+                        exit_code, log
+                    )));
+                }
+            }
+
             let code = match output.status.code() {
                 Some(exit_code) => exit_code.to_string(),
                 None => "'none'".to_string(),
@@ -73,6 +95,6 @@ impl CheckerTrait for Checker {
 
         updates.send(CheckStatus::Running, log);
 
-        return Ok(());
+        Ok(Health::Healthy)
     }
 }
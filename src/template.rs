@@ -0,0 +1,126 @@
+use std::time::Duration;
+
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+
+/// Renders a template containing `{{ key }}` placeholders
+///
+/// Each key is passed to `lookup`, with unknown keys rendering as an empty string. The result of each lookup is
+/// passed through `escape` before insertion, which lets callers produce e.g. valid JSON from a JSON template.
+pub fn render<L, E>(template: &str, lookup: L, escape: E) -> String
+where
+    L: Fn(&str) -> Option<String>,
+    E: Fn(&str) -> String,
+{
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find("{{") {
+        let Some(len) = rest[start + 2..].find("}}") else {
+            break;
+        };
+
+        out.push_str(&rest[..start]);
+
+        let key = rest[start + 2..start + 2 + len].trim();
+        if let Some(value) = lookup(key) {
+            out.push_str(&escape(&value));
+        }
+
+        rest = &rest[start + 2 + len + 2..];
+    }
+
+    out.push_str(rest);
+    out
+}
+
+/// Formats a duration for humans, e.g. `1h 2m 3s`
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    let (hours, minutes, seconds) = (secs / 3600, secs / 60 % 60, secs % 60);
+
+    let mut parts = Vec::new();
+    if hours > 0 {
+        parts.push(format!("{}h", hours));
+    }
+    if minutes > 0 {
+        parts.push(format!("{}m", minutes));
+    }
+    if seconds > 0 || parts.is_empty() {
+        parts.push(format!("{}s", seconds));
+    }
+
+    parts.join(" ")
+}
+
+/// Escapes values for insertion inside a JSON string literal
+pub fn json_escape(value: &str) -> String {
+    let quoted = serde_json::Value::String(value.to_owned()).to_string();
+    quoted[1..quoted.len() - 1].to_owned()
+}
+
+/// Everything but the characters URLs never need escaped, so a value can't add path segments or query parameters
+const URL_ESCAPED: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'.').remove(b'_').remove(b'~');
+
+/// Percent-encodes values for insertion into a URL
+pub fn url_escape(value: &str) -> String {
+    utf8_percent_encode(value, URL_ESCAPED).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn lookup<'a>(vars: &'a HashMap<&'a str, &'a str>) -> impl Fn(&str) -> Option<String> + 'a {
+        |key| vars.get(key).map(|v| v.to_string())
+    }
+
+    #[test]
+    fn test_render() {
+        let vars = HashMap::from([("name", "web"), ("labels.hostname", "host-1")]);
+
+        assert_eq!(render("plain", lookup(&vars), str::to_owned), "plain");
+        assert_eq!(
+            render("{{name}} on {{ labels.hostname }}", lookup(&vars), str::to_owned),
+            "web on host-1"
+        );
+        assert_eq!(render("[{{ missing }}]", lookup(&vars), str::to_owned), "[]");
+        assert_eq!(
+            render("unclosed {{ name", lookup(&vars), str::to_owned),
+            "unclosed {{ name"
+        );
+    }
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(Duration::from_millis(200)), "0s");
+        assert_eq!(format_duration(Duration::from_secs(45)), "45s");
+        assert_eq!(format_duration(Duration::from_secs(3600)), "1h");
+        assert_eq!(format_duration(Duration::from_secs(3723)), "1h 2m 3s");
+    }
+
+    #[test]
+    fn test_json_escape() {
+        let vars = HashMap::from([("msg", "say \"hi\"\n")]);
+
+        assert_eq!(
+            render(r#"{"text": "{{ msg }}"}"#, lookup(&vars), json_escape),
+            r#"{"text": "say \"hi\"\n"}"#
+        );
+    }
+
+    #[test]
+    fn test_url_escape() {
+        let vars = HashMap::from([("name", "http https://web-1/health?a=1&b"), ("id", "3")]);
+
+        assert_eq!(
+            render(
+                "https://status.example.com/checks/{{ id }}?name={{ name }}",
+                lookup(&vars),
+                url_escape
+            ),
+            "https://status.example.com/checks/3?name=http%20https%3A%2F%2Fweb-1%2Fhealth%3Fa%3D1%26b"
+        );
+    }
+}
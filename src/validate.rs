@@ -0,0 +1,440 @@
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+use std::time::Duration;
+
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use simple_eyre::eyre::{eyre, Result};
+
+use crate::{alert, config, dns, http, notify, sanitize, select, source, ssh};
+
+/// A single problem found in a config, located by its JSON path
+struct Problem {
+    path: String,
+    /// The name of the check the problem is in, if it got far enough to have one
+    name: Option<String>,
+    message: String,
+    /// Warnings are worth fixing, but the config still loads
+    warning: bool,
+}
+
+impl Problem {
+    fn new(path: impl Into<String>, message: impl Into<String>) -> Self {
+        Problem {
+            path: path.into(),
+            name: None,
+            message: message.into(),
+            warning: false,
+        }
+    }
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let warning = if self.warning { "warning: " } else { "" };
+        match self.name {
+            Some(ref name) => write!(f, "{} ({}): {}{}", self.path, name, warning, self.message),
+            None => write!(f, "{}: {}{}", self.path, warning, self.message),
+        }
+    }
+}
+
+fn join(prefix: &str, path: &str) -> String {
+    match (prefix, path) {
+        (prefix, ".") => prefix.to_owned(),
+        ("", path) => path.to_owned(),
+        (prefix, path) => format!("{}.{}", prefix, path),
+    }
+}
+
+/// Deserializes a value, recording unknown fields and the first error as problems under `prefix`
+fn deserialize<T: DeserializeOwned>(value: &Value, prefix: &str, problems: &mut Vec<Problem>) -> Option<T> {
+    let mut unknown = Vec::new();
+    let result = serde_path_to_error::deserialize(serde_ignored::Deserializer::new(value.clone(), &mut |path| {
+        unknown.push(path.to_string())
+    }));
+
+    problems.extend(
+        unknown
+            .into_iter()
+            .map(|path| Problem::new(join(prefix, &path), "unknown field")),
+    );
+
+    match result {
+        Ok(value) => Some(value),
+        Err(err) => {
+            problems.push(Problem::new(
+                join(prefix, &err.path().to_string()),
+                err.inner().to_string(),
+            ));
+            None
+        }
+    }
+}
+
+fn check_url(url: &str, path: String, problems: &mut Vec<Problem>) {
+    if let Err(err) = reqwest::Url::parse(url) {
+        problems.push(Problem::new(path, format!("invalid URL '{}': {}", url, err)));
+    }
+}
+
+fn check_notifiers(alerting: &alert::Config, problems: &mut Vec<Problem>) {
+    // Some notifiers start background tasks when constructed, which stop again when the runtime is dropped
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("a runtime can be built");
+    let _guard = rt.enter();
+
+    for (index, notifier) in alerting.notifiers.iter().enumerate() {
+        let path = format!("alerting.notifiers[{}].params", index);
+        if let Err(err) = notifier.clone().into_notifier() {
+            problems.push(Problem::new(path.clone(), format!("{:#}", err)));
+        }
+
+        match notifier {
+            notify::Config::Alertmanager(am_config) => {
+                for (url_index, url) in am_config.base_urls.iter().enumerate() {
+                    check_url(url, format!("{}.baseURLs[{}]", path, url_index), problems);
+                }
+            }
+            notify::Config::Webhook(webhook_config) => {
+                check_url(&webhook_config.url, format!("{}.url", path), problems);
+            }
+            notify::Config::Email(_) => {}
+        }
+    }
+
+    if let Some(ref base_url) = alerting.base_url {
+        check_url(base_url, "alerting.baseURL".to_owned(), problems);
+    }
+}
+
+/// A check definition which could be built
+struct Built {
+    name: String,
+    labels: HashMap<String, String>,
+    /// What the check is told apart from others by when loading
+    identity: String,
+}
+
+/// Checks a single check definition, returning what's needed of it to check it against the others if it could be built
+fn check_definition(index: usize, prefix: &str, value: &Value, problems: &mut Vec<Problem>) -> Option<Built> {
+    let mut check_problems = Vec::new();
+
+    let definition: Option<config::CheckDefinition> = deserialize(value, prefix, &mut check_problems);
+    let mut built = None;
+
+    if let Some(definition) = definition {
+        // Unknown fields at the top level and in params are hidden by the flattened check config
+        if let Some(object) = value.as_object() {
+            check_problems.extend(
+                object
+                    .keys()
+                    .filter(|key| !config::CHECK_DEFINITION_FIELDS.contains(&key.as_str()))
+                    .map(|key| Problem::new(join(prefix, key), "unknown field")),
+            );
+        }
+
+        let params_prefix = join(prefix, "params");
+        let params = &value["params"];
+        match definition.config {
+            config::CheckConfig::Http(_) => {
+                deserialize::<http::Config>(params, &params_prefix, &mut check_problems);
+            }
+            config::CheckConfig::Dns(_) => {
+                deserialize::<dns::Config>(params, &params_prefix, &mut check_problems);
+            }
+            config::CheckConfig::Ssh(_) => {
+                deserialize::<ssh::Config>(params, &params_prefix, &mut check_problems);
+            }
+        }
+
+        let policy = &definition.alert_policy;
+        let zero = [
+            ("checkTimeout", definition.check_timeout),
+            ("alertPolicy.checkInterval", policy.check_interval),
+            ("alertPolicy.recheckInterval", policy.recheck_interval),
+        ];
+        for (field, duration) in zero {
+            if duration == Duration::ZERO {
+                check_problems.push(Problem::new(join(prefix, field), "must be greater than zero"));
+            }
+        }
+
+        if !(0.0..=1.0).contains(&policy.jitter) {
+            check_problems.push(Problem::new(
+                join(prefix, "alertPolicy.jitter"),
+                "must be between 0 and 1",
+            ));
+        }
+
+        let interval = match policy.schedule {
+            Some(ref schedule) => schedule
+                .shortest_gap(policy.timezone)
+                .map(|gap| (gap, "the time between runs of alertPolicy.schedule")),
+            None => Some((policy.check_interval, "alertPolicy.checkInterval")),
+        };
+        if let Some((interval, field)) = interval {
+            if definition.check_timeout > interval {
+                check_problems.push(Problem::new(
+                    join(prefix, "checkTimeout"),
+                    format!(
+                        "{:?} is longer than {} ({:?})",
+                        definition.check_timeout, field, interval
+                    ),
+                ));
+            }
+        }
+
+        let identity = definition.identity();
+        match (definition.config.into_check(index), identity) {
+            (Ok(checker), Ok(identity)) => {
+                built = Some(Built {
+                    name: checker.name(),
+                    labels: definition.labels,
+                    identity,
+                })
+            }
+            (Err(err), _) | (_, Err(err)) => {
+                check_problems.push(Problem::new(join(prefix, "params"), format!("{:#}", err)))
+            }
+        }
+    }
+
+    for mut problem in check_problems {
+        problem.name = built.as_ref().map(|built| built.name.clone());
+        problems.push(problem);
+    }
+
+    built
+}
+
+/// Finds every problem in a config, rather than stopping at the first
+fn validate(merged: &source::Merged, label_selector: Option<&select::Term>) -> Vec<Problem> {
+    let mut problems = Vec::new();
+
+    // Checks are validated one by one, so a mistake in one doesn't hide mistakes in the others
+    let mut top = merged.raw.clone();
+    let checks = match top.get_mut("checks").map(Value::take) {
+        Some(Value::Array(checks)) => {
+            top["checks"] = Value::Array(Vec::new());
+            checks
+        }
+        Some(checks) => {
+            top["checks"] = checks;
+            Vec::new()
+        }
+        None => Vec::new(),
+    };
+
+    let config: Option<config::Config> = deserialize(&top, "", &mut problems);
+
+    let mut built = Vec::new();
+    for (index, value) in checks.iter().enumerate() {
+        if let Some(check) = check_definition(index, &merged.check_path(index), value, &mut problems) {
+            built.push((index, check));
+        }
+    }
+
+    // Loading rejects identical checks in different files, but runs those within a file, told apart by their order
+    for (position, (index, check)) in built.iter().enumerate() {
+        let duplicate = built[..position]
+            .iter()
+            .find(|(_, other_check)| other_check.identity == check.identity);
+        if let Some((other, _)) = duplicate {
+            let warning = merged.same_file(*index, *other);
+            problems.push(Problem {
+                path: merged.check_path(*index),
+                name: Some(check.name.clone()),
+                message: format!(
+                    "duplicate of {}, with the same type, params and labels{}",
+                    merged.check_path(*other),
+                    if warning { ", so both run" } else { "" }
+                ),
+                warning,
+            });
+        }
+    }
+
+    let matches_any = |term: &select::Term| built.iter().any(|(_, check)| term.matches(&check.labels));
+
+    if let Some(term) = label_selector {
+        if !matches_any(term) {
+            problems.push(Problem::new("--select", "matches no checks"));
+        }
+    }
+
+    if let Some(config) = config {
+        if let Some(ref alerting) = config.alerting {
+            check_notifiers(alerting, &mut problems);
+        }
+
+        if let Err(err) = sanitize::Sanitizer::new(config.output) {
+            problems.push(Problem::new("output.redact", format!("{:#}", err)));
+        }
+
+        for (index, window) in config.maintenance.iter().enumerate() {
+            if let Err(err) = window.check_schedule() {
+                problems.push(Problem::new(
+                    format!("maintenance[{}].once", index),
+                    format!("{:#}", err),
+                ));
+            }
+
+            let Some(ref sel) = window.select else {
+                continue;
+            };
+
+            let path = format!("maintenance[{}].select", index);
+            match sel.parse::<select::Term>() {
+                Ok(term) if !matches_any(&term) => problems.push(Problem::new(path, "matches no checks")),
+                Ok(_) => {}
+                Err(err) => problems.push(Problem::new(path, format!("{:#}", err))),
+            }
+        }
+    }
+
+    problems
+}
+
+pub fn run_validate(
+    config_files: Vec<String>,
+    format: Option<config::Format>,
+    label_selector: Option<select::Term>,
+) -> Result<()> {
+    let described = config_files.join("', '");
+    let merged = source::Source::new(config_files, format).read()?;
+
+    let problems = validate(&merged, label_selector.as_ref());
+    for problem in problems.iter() {
+        println!("{}", problem);
+    }
+
+    let errors = problems.iter().filter(|problem| !problem.warning).count();
+    if errors > 0 {
+        return Err(eyre!("{} problem(s) found in '{}'", errors, described));
+    }
+
+    println!("'{}' is valid", described);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn check(url: &str, hostname: &str) -> Value {
+        json!({
+            "type": "http",
+            "params": { "url": url },
+            "labels": { "hostname": hostname },
+            "annotations": {},
+            "checkTimeout": 10,
+            "retryPolicy": { "maxRetries": 1, "initial": 1, "multiplier": 1 },
+            "alertPolicy": { "checkInterval": 60, "recheckInterval": 30 },
+        })
+    }
+
+    fn messages(raw: &Value) -> Vec<String> {
+        let merged = source::Merged {
+            raw: raw.clone(),
+            check_origins: Vec::new(),
+        };
+        validate(&merged, None).iter().map(Problem::to_string).collect()
+    }
+
+    #[test]
+    fn test_valid() {
+        let raw = json!({ "checks": [check("http://web-1/", "web-1"), check("http://web-2/", "web-2")] });
+
+        assert!(messages(&raw).is_empty());
+    }
+
+    #[test]
+    fn test_reports_every_problem() {
+        let mut typo = check("http://web-1/", "web-1");
+        typo["alertPolicy"]["checkIntervall"] = json!(60);
+        typo["params"]["insecure"] = json!(true);
+        typo["label"] = json!({});
+        let mut slow = check("http://web-2/", "web-2");
+        slow["checkTimeout"] = json!(120);
+
+        let raw = json!({
+            "checks": [typo, slow, check("not a url", "web-3"), check("http://web-2/", "web-2")],
+            "maintenance": [{ "select": "hostname:db-1", "once": { "start": "2024-01-01T00:00:00Z", "end": "2024-01-02T00:00:00Z" } }],
+            "output": { "redact": ["("] },
+            "alerting": {
+                "realertInterval": 60,
+                "allowOutputAnnotation": false,
+                "notifiers": [{ "type": "webhook", "params": { "url": "http://hooks/", "headers": { "bad header": "x" } } }],
+            },
+            "defaults": { "http": {} },
+        });
+
+        assert_eq!(
+            messages(&raw),
+            vec![
+                "defaults: unknown field",
+                "checks[0].alertPolicy.checkIntervall (http http://web-1/): unknown field",
+                "checks[0].label (http http://web-1/): unknown field",
+                "checks[0].params.insecure (http http://web-1/): unknown field",
+                "checks[1].checkTimeout (http http://web-2/): 120s is longer than alertPolicy.checkInterval (60s)",
+                "checks[2].params: Invalid URL 'not a url': relative URL without a base",
+                "checks[3] (http http://web-2/): warning: duplicate of checks[1], with the same type, params and labels, so both run",
+                "alerting.notifiers[0].params: Invalid webhook header name 'bad header': invalid HTTP header name",
+                "output.redact: Invalid redaction regex '(': regex parse error:\n    (\n    ^\nerror: unclosed group",
+                "maintenance[0].select: matches no checks",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_paths_name_files() {
+        let merged = source::Merged {
+            raw: json!({ "checks": [check("http://web-1/", "web-1"), check("http://web-1/", "web-1")] }),
+            check_origins: vec![("a.json".to_owned(), 0), ("conf.d/b.yaml".to_owned(), 0)],
+        };
+
+        let problems: Vec<String> = validate(&merged, None).iter().map(Problem::to_string).collect();
+        assert_eq!(
+            problems,
+            vec!["conf.d/b.yaml:checks[0] (http http://web-1/): duplicate of a.json:checks[0], with the same type, params and labels"]
+        );
+    }
+
+    #[test]
+    fn test_duplicates_within_a_file() {
+        // Loading runs identical checks in one file, so they're only a warning
+        let raw = json!({ "checks": [check("http://web-1/", "web-1"), check("http://web-1/", "web-1")] });
+
+        assert_eq!(
+            messages(&raw),
+            vec!["checks[1] (http http://web-1/): warning: duplicate of checks[0], with the same type, params and labels, so both run"]
+        );
+    }
+
+    #[test]
+    fn test_scheduled_timeout() {
+        let mut slow = check("http://web-1/", "web-1");
+        slow["checkTimeout"] = json!(120);
+        slow["alertPolicy"]["schedule"] = json!("* * * * *");
+
+        assert_eq!(
+            messages(&json!({ "checks": [slow] })),
+            vec!["checks[0].checkTimeout (http http://web-1/): 120s is longer than the time between runs of alertPolicy.schedule (60s)"]
+        );
+    }
+
+    #[test]
+    fn test_type_errors_have_paths() {
+        let mut bad = check("http://web-1/", "web-1");
+        bad["retryPolicy"]["maxRetries"] = json!("three");
+
+        let problems = messages(&json!({ "checks": [bad] }));
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].starts_with("checks[0].retryPolicy.maxRetries: invalid type"));
+    }
+}
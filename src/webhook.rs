@@ -0,0 +1,115 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use log::error;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE};
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde_json::json;
+use serde_with::{serde_as, DurationSeconds};
+use simple_eyre::eyre::{eyre, Result, WrapErr};
+
+use crate::notify::{Alert, Notifier as NotifierTrait};
+use crate::template;
+
+fn default_timeout() -> Duration {
+    Duration::from_secs(10)
+}
+
+#[serde_as]
+#[derive(Clone, Deserialize, JsonSchema, Debug)]
+#[schemars(rename = "WebhookConfig")]
+#[serde(rename_all = "camelCase")]
+pub struct Config {
+    pub url: String,
+    #[serde(default)]
+    headers: HashMap<String, String>,
+    /// How many seconds to wait for each request before giving up on it
+    #[serde_as(as = "DurationSeconds<f64>")]
+    #[schemars(with = "f64")]
+    #[serde(default = "default_timeout")]
+    timeout: Duration,
+    /// JSON payload template used when an alert starts firing
+    firing_template: Option<String>,
+    /// JSON payload template used when an alert is resolved
+    resolved_template: Option<String>,
+}
+
+pub struct Notifier {
+    client: reqwest::Client,
+    config: Config,
+}
+
+impl Notifier {
+    pub fn new(config: Config) -> Result<Self> {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+
+        for (name, value) in config.headers.iter() {
+            headers.insert(
+                HeaderName::try_from(name).wrap_err(format!("Invalid webhook header name '{}'", name))?,
+                HeaderValue::try_from(value).wrap_err(format!("Invalid value for webhook header '{}'", name))?,
+            );
+        }
+
+        let client = reqwest::ClientBuilder::new()
+            .default_headers(headers)
+            .timeout(config.timeout)
+            .build()
+            .wrap_err("Unable to construct http client")?;
+
+        Ok(Notifier { client, config })
+    }
+
+    fn payload(&self, alert: &Alert) -> String {
+        let template = if alert.is_resolved() {
+            &self.config.resolved_template
+        } else {
+            &self.config.firing_template
+        };
+
+        match template {
+            Some(template) => template::render(template, |key| alert.lookup(key), template::json_escape),
+            None => json!({
+                "status": alert.lookup("status"),
+                "name": alert.name,
+                "startsAt": alert.lookup("startsAt"),
+                "endsAt": alert.lookup("endsAt"),
+                "labels": alert.labels,
+                "annotations": alert.annotations,
+            })
+            .to_string(),
+        }
+    }
+
+    async fn send(&self, payload: String) -> Result<()> {
+        let response = self
+            .client
+            .post(&self.config.url)
+            .body(payload)
+            .send()
+            .await
+            .wrap_err("Error making webhook request")?;
+
+        let status = response.status();
+        if !status.is_success() {
+            return Err(eyre!("Webhook responded with '{}'", status));
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl NotifierTrait for Notifier {
+    fn name(&self) -> String {
+        format!("webhook {}", self.config.url)
+    }
+
+    async fn notify(&self, alert: &Alert) {
+        if let Err(e) = self.send(self.payload(alert)).await {
+            error!("Failure sending alert to {}: {:#}", self.name(), e);
+        }
+    }
+}